@@ -0,0 +1,13 @@
+CREATE TABLE credit_holds (
+    "id" SERIAL PRIMARY KEY,
+    "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    "resolved_at" TIMESTAMP WITH TIME ZONE,
+    "username" VARCHAR(255) NOT NULL,
+    "amount" INTEGER NOT NULL,
+    "machine" INTEGER,
+    "slot" INTEGER,
+    "status" VARCHAR(32) NOT NULL DEFAULT 'held',
+    "error" TEXT
+);
+
+CREATE INDEX credit_holds_username_status ON credit_holds (username, status);
@@ -0,0 +1,14 @@
+CREATE TABLE credit_transactions (
+    "id" SERIAL PRIMARY KEY,
+    "timestamp" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    "username" VARCHAR(255) NOT NULL,
+    "kind" VARCHAR(32) NOT NULL,
+    "amount" BIGINT NOT NULL,
+    "balance_before" BIGINT,
+    "balance_after" BIGINT NOT NULL,
+    "actor" VARCHAR(255) NOT NULL,
+    "reason" TEXT,
+    "drop_id" INTEGER
+);
+
+CREATE INDEX credit_transactions_username_timestamp ON credit_transactions (username, "timestamp" DESC);
@@ -0,0 +1,7 @@
+CREATE TABLE idempotency_keys (
+    "username" VARCHAR(255) NOT NULL,
+    "key" VARCHAR(255) NOT NULL,
+    "created_at" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    "outcome" TEXT,
+    PRIMARY KEY (username, "key")
+);
@@ -0,0 +1,8 @@
+DROP INDEX drops_status;
+
+ALTER TABLE drops
+    DROP COLUMN "status",
+    DROP COLUMN "error",
+    DROP COLUMN "hold",
+    DROP COLUMN "resolved_by",
+    DROP COLUMN "resolved_at";
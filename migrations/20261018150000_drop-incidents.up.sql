@@ -0,0 +1,8 @@
+ALTER TABLE drops
+    ADD COLUMN "status" VARCHAR(32) NOT NULL DEFAULT 'success',
+    ADD COLUMN "error" TEXT,
+    ADD COLUMN "hold" INTEGER,
+    ADD COLUMN "resolved_by" VARCHAR(255),
+    ADD COLUMN "resolved_at" TIMESTAMP WITH TIME ZONE;
+
+CREATE INDEX drops_status ON drops (status);
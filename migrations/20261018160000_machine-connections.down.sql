@@ -0,0 +1,3 @@
+ALTER TABLE machines
+    DROP COLUMN "url",
+    DROP COLUMN "secret";
@@ -0,0 +1,3 @@
+ALTER TABLE machines
+    ADD COLUMN "url" TEXT,
+    ADD COLUMN "secret" TEXT;
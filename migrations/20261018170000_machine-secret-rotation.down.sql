@@ -0,0 +1,3 @@
+ALTER TABLE machines
+    DROP COLUMN "previous_secret",
+    DROP COLUMN "previous_secret_expires";
@@ -0,0 +1,3 @@
+ALTER TABLE machines
+    ADD COLUMN "previous_secret" TEXT,
+    ADD COLUMN "previous_secret_expires" TIMESTAMP WITH TIME ZONE;
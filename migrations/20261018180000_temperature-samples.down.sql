@@ -0,0 +1,3 @@
+ALTER TABLE machines DROP COLUMN "max_temp";
+
+DROP TABLE temperature_samples;
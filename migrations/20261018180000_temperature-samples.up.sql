@@ -0,0 +1,14 @@
+CREATE TABLE temperature_samples (
+    "id" SERIAL PRIMARY KEY,
+    "machine" INTEGER NOT NULL,
+    "timestamp" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    "temp" REAL NOT NULL,
+    CONSTRAINT fk_machine
+        FOREIGN KEY (machine)
+        REFERENCES machines(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX temperature_samples_machine_timestamp ON temperature_samples (machine, "timestamp");
+
+ALTER TABLE machines ADD COLUMN "max_temp" REAL;
@@ -0,0 +1,12 @@
+CREATE UNIQUE INDEX machines_name ON machines (name);
+
+CREATE TABLE audit_log (
+    "id" SERIAL PRIMARY KEY,
+    "timestamp" TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT (now() AT TIME ZONE 'UTC'),
+    "actor" VARCHAR(255) NOT NULL,
+    "action" VARCHAR(64) NOT NULL,
+    "target" VARCHAR(255) NOT NULL,
+    "details" TEXT
+);
+
+CREATE INDEX audit_log_target ON audit_log (target);
@@ -0,0 +1 @@
+ALTER TABLE machines DROP COLUMN "slot_offset";
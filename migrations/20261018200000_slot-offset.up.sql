@@ -0,0 +1,2 @@
+-- bubbler's numbering is one ahead of ours, which used to be hardcoded
+ALTER TABLE machines ADD COLUMN "slot_offset" INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+ALTER TABLE machines
+    DROP COLUMN "maintenance_message",
+    DROP COLUMN "maintenance_start",
+    DROP COLUMN "maintenance_end";
@@ -0,0 +1,4 @@
+ALTER TABLE machines
+    ADD COLUMN "maintenance_message" TEXT,
+    ADD COLUMN "maintenance_start" TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN "maintenance_end" TIMESTAMP WITH TIME ZONE;
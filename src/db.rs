@@ -1,5 +1,11 @@
+pub mod api_keys;
+pub mod audit;
 pub mod drops;
+pub mod holds;
+pub mod idempotency;
 pub mod items;
 pub mod machines;
 pub mod models;
 pub mod slots;
+pub mod temperatures;
+pub mod transactions;
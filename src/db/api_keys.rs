@@ -0,0 +1,119 @@
+use crate::db::models;
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiScope {
+    /// See what's in the machines and items
+    ReadDrinks,
+    /// Drop for the user named in X-User-Info
+    Drop,
+    /// Add, change and remove items
+    ManageItems,
+    /// Read transactions, incidents and machine history
+    ReadStats,
+}
+
+impl ApiScope {
+    pub const ALL: &'static [ApiScope] = &[
+        ApiScope::ReadDrinks,
+        ApiScope::Drop,
+        ApiScope::ManageItems,
+        ApiScope::ReadStats,
+    ];
+
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ReadDrinks => "drinks:read",
+            ApiScope::Drop => "drinks:drop",
+            ApiScope::ManageItems => "items:write",
+            ApiScope::ReadStats => "stats:read",
+        }
+    }
+
+    #[must_use]
+    pub fn parse(scope: &str) -> Option<Self> {
+        ApiScope::ALL
+            .iter()
+            .find(|known| known.as_str() == scope)
+            .copied()
+    }
+}
+
+pub async fn create_api_key(
+    pool: &Pool<Postgres>,
+    name: &str,
+    prefix: &str,
+    key_hash: &str,
+    scopes: &[ApiScope],
+    created_by: &str,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<models::ApiKey, sqlx::Error> {
+    let scopes: Vec<&str> = scopes.iter().map(ApiScope::as_str).collect();
+    sqlx::query_as::<_, models::ApiKey>(
+        "INSERT INTO api_keys(name, prefix, key_hash, scopes, created_by, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING *",
+    )
+    .bind(name)
+    .bind(prefix)
+    .bind(key_hash)
+    .bind(scopes)
+    .bind(created_by)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_api_keys(pool: &Pool<Postgres>) -> Result<Vec<models::ApiKey>, sqlx::Error> {
+    sqlx::query_as::<_, models::ApiKey>("SELECT * FROM api_keys ORDER BY id ASC")
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_api_key(pool: &Pool<Postgres>, id: i32) -> Result<models::ApiKey, sqlx::Error> {
+    sqlx::query_as::<_, models::ApiKey>("SELECT * FROM api_keys WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn get_api_key_by_prefix(
+    pool: &Pool<Postgres>,
+    prefix: &str,
+) -> Result<models::ApiKey, sqlx::Error> {
+    sqlx::query_as::<_, models::ApiKey>("SELECT * FROM api_keys WHERE prefix = $1")
+        .bind(prefix)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn revoke_api_key(
+    pool: &Pool<Postgres>,
+    id: i32,
+    revoked_by: &str,
+) -> Result<models::ApiKey, sqlx::Error> {
+    sqlx::query_as::<_, models::ApiKey>(
+        "UPDATE api_keys SET revoked_at = now(), revoked_by = $1
+        WHERE id = $2 AND revoked_at IS NULL
+        RETURNING *",
+    )
+    .bind(revoked_by)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Record that a key was used, at most once a minute to keep writes down
+pub async fn touch_api_key(pool: &Pool<Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE api_keys SET last_used_at = now()
+        WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < now() - INTERVAL '1 minute')",
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,35 @@
+use crate::db::models;
+use sqlx::{Pool, Postgres};
+
+/// Record an admin change, with `details` usually holding the before and after
+pub async fn log_audit(
+    pool: &Pool<Postgres>,
+    actor: &str,
+    action: &str,
+    target: &str,
+    details: &serde_json::Value,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO audit_log(actor, action, target, details) VALUES ($1, $2, $3, $4)")
+        .bind(actor)
+        .bind(action)
+        .bind(target)
+        .bind(details.to_string())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_audit_log(
+    pool: &Pool<Postgres>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<models::AuditEntry>, sqlx::Error> {
+    sqlx::query_as::<_, models::AuditEntry>(
+        "SELECT * FROM audit_log ORDER BY timestamp DESC, id DESC LIMIT $1 OFFSET $2",
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
@@ -1,10 +1,37 @@
 use crate::db::models;
 use sqlx::{Pool, Postgres};
 
-pub async fn log_drop(pool: &Pool<Postgres>, drop: &models::Drop) -> Result<(), sqlx::Error> {
-    sqlx::query(
-        "INSERT INTO drops(username, machine, slot, item, item_name, item_price) 
-        VALUES ($1, $2, $3, $4, $5, $6)",
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropStatus {
+    Success,
+    /// The machine reported an error after the drop was sent
+    Failed,
+    /// The drop was sent but we never heard back
+    Unknown,
+    /// An incident a drink admin decided did not dispense
+    Refunded,
+    /// An incident a drink admin decided did dispense
+    Confirmed,
+}
+
+impl DropStatus {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropStatus::Success => "success",
+            DropStatus::Failed => "failed",
+            DropStatus::Unknown => "unknown",
+            DropStatus::Refunded => "refunded",
+            DropStatus::Confirmed => "confirmed",
+        }
+    }
+}
+
+pub async fn log_drop(pool: &Pool<Postgres>, drop: &models::Drop) -> Result<i32, sqlx::Error> {
+    sqlx::query_scalar(
+        "INSERT INTO drops(username, machine, slot, item, item_name, item_price, status, error, hold) 
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        RETURNING id",
     )
     .bind(&drop.username)
     .bind(drop.machine)
@@ -12,6 +39,83 @@ pub async fn log_drop(pool: &Pool<Postgres>, drop: &models::Drop) -> Result<(),
     .bind(drop.item)
     .bind(&drop.item_name)
     .bind(drop.item_price)
+    .bind(&drop.status)
+    .bind(&drop.error)
+    .bind(drop.hold)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_drop(pool: &Pool<Postgres>, id: i32) -> Result<models::Drop, sqlx::Error> {
+    sqlx::query_as::<_, models::Drop>("SELECT * FROM drops WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn get_incidents(pool: &Pool<Postgres>) -> Result<Vec<models::Drop>, sqlx::Error> {
+    sqlx::query_as::<_, models::Drop>(
+        "SELECT * FROM drops
+        WHERE status IN ($1, $2)
+        ORDER BY timestamp ASC",
+    )
+    .bind(DropStatus::Failed.as_str())
+    .bind(DropStatus::Unknown.as_str())
+    .fetch_all(pool)
+    .await
+}
+
+/// The drop a hold was placed for, if one was logged
+pub async fn get_drop_for_hold(
+    pool: &Pool<Postgres>,
+    hold: i32,
+) -> Result<Option<models::Drop>, sqlx::Error> {
+    sqlx::query_as::<_, models::Drop>("SELECT * FROM drops WHERE hold = $1")
+        .bind(hold)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Mark an incident as resolved, but only if it is still `from`
+///
+/// Returns `None` if someone else resolved it first, so the caller can tell it
+/// lost the race rather than charging or refunding twice.
+pub async fn resolve_drop(
+    pool: &Pool<Postgres>,
+    id: i32,
+    from: &str,
+    status: DropStatus,
+    resolved_by: &str,
+) -> Result<Option<models::Drop>, sqlx::Error> {
+    sqlx::query_as::<_, models::Drop>(
+        "UPDATE drops
+                SET status = $1, resolved_by = $2, resolved_at = (now() AT TIME ZONE 'UTC')
+                WHERE id = $3 AND status = $4
+                RETURNING *",
+    )
+    .bind(status.as_str())
+    .bind(resolved_by)
+    .bind(id)
+    .bind(from)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Put a resolved incident back to `status`, when its resolution couldn't be carried out
+pub async fn reopen_drop(
+    pool: &Pool<Postgres>,
+    id: i32,
+    resolved: DropStatus,
+    status: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE drops
+                SET status = $1, resolved_by = NULL, resolved_at = NULL
+                WHERE id = $2 AND status = $3",
+    )
+    .bind(status)
+    .bind(id)
+    .bind(resolved.as_str())
     .execute(pool)
     .await?;
 
@@ -0,0 +1,162 @@
+use crate::db::models;
+use sqlx::{Pool, Postgres};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HoldStatus {
+    /// Credits are reserved while the machine dispenses
+    Held,
+    /// The machine dispensed and the user was debited
+    Committed,
+    /// The drop failed, so the credits were never taken
+    Released,
+    /// The machine dispensed but the debit could not be written
+    Unreconciled,
+}
+
+impl HoldStatus {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HoldStatus::Held => "held",
+            HoldStatus::Committed => "committed",
+            HoldStatus::Released => "released",
+            HoldStatus::Unreconciled => "unreconciled",
+        }
+    }
+}
+
+/// Reserve `amount` credits for `username`, returning the hold id, or `None` if
+/// the balance minus any outstanding holds cannot cover it
+pub async fn place_hold(
+    pool: &Pool<Postgres>,
+    username: &str,
+    balance: i64,
+    amount: i32,
+    machine: i32,
+    slot: i32,
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Serialize hold placement per user so two drops can't both see the same balance
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(username)
+        .execute(&mut tx)
+        .await?;
+
+    let outstanding: Option<i64> = sqlx::query_scalar(
+        "SELECT SUM(amount) FROM credit_holds
+        WHERE username = $1 AND status IN ($2, $3)",
+    )
+    .bind(username)
+    .bind(HoldStatus::Held.as_str())
+    .bind(HoldStatus::Unreconciled.as_str())
+    .fetch_one(&mut tx)
+    .await?;
+
+    if balance - outstanding.unwrap_or(0) < i64::from(amount) {
+        tx.rollback().await?;
+        return Ok(None);
+    }
+
+    let id: i32 = sqlx::query_scalar(
+        "INSERT INTO credit_holds(username, amount, machine, slot, status)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id",
+    )
+    .bind(username)
+    .bind(amount)
+    .bind(machine)
+    .bind(slot)
+    .bind(HoldStatus::Held.as_str())
+    .fetch_one(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(id))
+}
+
+pub async fn resolve_hold(
+    pool: &Pool<Postgres>,
+    id: i32,
+    status: HoldStatus,
+    error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE credit_holds
+                SET status = $1, error = $2, resolved_at = (now() AT TIME ZONE 'UTC')
+                WHERE id = $3",
+    )
+    .bind(status.as_str())
+    .bind(error)
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Settle an unreconciled hold, but only if nobody else has settled it yet
+///
+/// Returns `None` if it wasn't unreconciled any more, like `drops::resolve_drop`.
+pub async fn settle_hold(
+    pool: &Pool<Postgres>,
+    id: i32,
+    status: HoldStatus,
+    error: Option<&str>,
+) -> Result<Option<models::CreditHold>, sqlx::Error> {
+    sqlx::query_as::<_, models::CreditHold>(
+        "UPDATE credit_holds
+                SET status = $1, error = $2, resolved_at = (now() AT TIME ZONE 'UTC')
+                WHERE id = $3 AND status = $4
+                RETURNING *",
+    )
+    .bind(status.as_str())
+    .bind(error)
+    .bind(id)
+    .bind(HoldStatus::Unreconciled.as_str())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Put a settled hold back to unreconciled, when the settlement couldn't be carried out
+pub async fn reopen_hold(
+    pool: &Pool<Postgres>,
+    id: i32,
+    settled: HoldStatus,
+    error: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE credit_holds
+                SET status = $1, error = $2, resolved_at = NULL
+                WHERE id = $3 AND status = $4",
+    )
+    .bind(HoldStatus::Unreconciled.as_str())
+    .bind(error)
+    .bind(id)
+    .bind(settled.as_str())
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_holds_by_status(
+    pool: &Pool<Postgres>,
+    status: &str,
+) -> Result<Vec<models::CreditHold>, sqlx::Error> {
+    sqlx::query_as::<_, models::CreditHold>(
+        "SELECT * FROM credit_holds
+        WHERE status = $1
+        ORDER BY created_at ASC",
+    )
+    .bind(status)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn get_hold(pool: &Pool<Postgres>, id: i32) -> Result<models::CreditHold, sqlx::Error> {
+    sqlx::query_as::<_, models::CreditHold>("SELECT * FROM credit_holds WHERE id = $1")
+        .bind(id)
+        .fetch_one(pool)
+        .await
+}
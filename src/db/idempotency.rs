@@ -0,0 +1,87 @@
+use crate::db::models;
+use sqlx::{Pool, Postgres};
+
+/// Claim `key` for `username`, returning `None` if the key is new, or the
+/// existing record if the key was already used within `window_secs`
+///
+/// A claim that never got an outcome within `stale_secs` was abandoned, by a
+/// crash or a failed write, and is claimed again.
+pub async fn claim_key(
+    pool: &Pool<Postgres>,
+    username: &str,
+    key: &str,
+    window_secs: i64,
+    stale_secs: i64,
+) -> Result<Option<models::IdempotencyKey>, sqlx::Error> {
+    // Keys outside the window are free to be reused
+    sqlx::query(
+        "DELETE FROM idempotency_keys
+        WHERE username = $1 AND key = $2
+            AND (created_at < (now() AT TIME ZONE 'UTC') - make_interval(secs => $3)
+                OR (outcome IS NULL
+                    AND created_at < (now() AT TIME ZONE 'UTC') - make_interval(secs => $4)))",
+    )
+    .bind(username)
+    .bind(key)
+    .bind(window_secs as f64)
+    .bind(stale_secs as f64)
+    .execute(pool)
+    .await?;
+
+    let claimed = sqlx::query(
+        "INSERT INTO idempotency_keys(username, key) VALUES ($1, $2)
+        ON CONFLICT DO NOTHING",
+    )
+    .bind(username)
+    .bind(key)
+    .execute(pool)
+    .await?
+    .rows_affected()
+        == 1;
+    if claimed {
+        return Ok(None);
+    }
+
+    sqlx::query_as::<_, models::IdempotencyKey>(
+        "SELECT * FROM idempotency_keys WHERE username = $1 AND key = $2",
+    )
+    .bind(username)
+    .bind(key)
+    .fetch_one(pool)
+    .await
+    .map(Some)
+}
+
+pub async fn complete_key(
+    pool: &Pool<Postgres>,
+    username: &str,
+    key: &str,
+    outcome: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE idempotency_keys
+                SET outcome = $1
+                WHERE username = $2 AND key = $3",
+    )
+    .bind(outcome)
+    .bind(username)
+    .bind(key)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn release_key(
+    pool: &Pool<Postgres>,
+    username: &str,
+    key: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE username = $1 AND key = $2")
+        .bind(username)
+        .bind(key)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
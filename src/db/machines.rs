@@ -1,4 +1,5 @@
 use crate::db::models;
+use chrono::{DateTime, Utc};
 use sqlx::{Pool, Postgres};
 
 pub async fn get_active_machines(
@@ -26,3 +27,128 @@ pub async fn get_machine(
         .fetch_one(pool)
         .await
 }
+
+/// Look up a machine whether or not it's active, for admin tools
+pub async fn get_any_machine(
+    pool: &Pool<Postgres>,
+    name: &str,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>("SELECT * FROM machines WHERE name = $1")
+        .bind(name)
+        .fetch_one(pool)
+        .await
+}
+
+pub async fn create_machine(
+    pool: &Pool<Postgres>,
+    name: &str,
+    display_name: &str,
+    active: bool,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "INSERT INTO machines(name, display_name, active) VALUES ($1, $2, $3) RETURNING *",
+    )
+    .bind(name)
+    .bind(display_name)
+    .bind(active)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn update_machine(
+    pool: &Pool<Postgres>,
+    id: i32,
+    name: &str,
+    display_name: &str,
+    active: bool,
+    slot_offset: i32,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "UPDATE machines SET name = $1, display_name = $2, active = $3, slot_offset = $4 WHERE id = $5 RETURNING *",
+    )
+    .bind(name)
+    .bind(display_name)
+    .bind(active)
+    .bind(slot_offset)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Delete a machine along with its slots and temperature history
+///
+/// Drop logs keep the old machine id, so deactivating is usually the better choice.
+pub async fn delete_machine(pool: &Pool<Postgres>, id: i32) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM machines WHERE id = $1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_connection(
+    pool: &Pool<Postgres>,
+    id: i32,
+    url: Option<&str>,
+    secret: Option<&str>,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "UPDATE machines SET url = $1, secret = $2 WHERE id = $3 RETURNING *",
+    )
+    .bind(url)
+    .bind(secret)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Swap in a new secret, keeping the current one valid until `previous_expires`
+pub async fn rotate_secret(
+    pool: &Pool<Postgres>,
+    id: i32,
+    secret: &str,
+    previous_expires: DateTime<Utc>,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "UPDATE machines SET previous_secret = secret, previous_secret_expires = $1, secret = $2 WHERE id = $3 RETURNING *",
+    )
+    .bind(previous_expires)
+    .bind(secret)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn set_max_temp(
+    pool: &Pool<Postgres>,
+    id: i32,
+    max_temp: Option<f32>,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "UPDATE machines SET max_temp = $1 WHERE id = $2 RETURNING *",
+    )
+    .bind(max_temp)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Schedule a maintenance window, or clear it by passing `None` for `start`
+pub async fn set_maintenance(
+    pool: &Pool<Postgres>,
+    id: i32,
+    message: Option<&str>,
+    start: Option<DateTime<Utc>>,
+    end: Option<DateTime<Utc>>,
+) -> Result<models::Machine, sqlx::Error> {
+    sqlx::query_as::<_, models::Machine>(
+        "UPDATE machines SET maintenance_message = $1, maintenance_start = $2, maintenance_end = $3 WHERE id = $4 RETURNING *",
+    )
+    .bind(message)
+    .bind(start)
+    .bind(end)
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
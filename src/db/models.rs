@@ -1,19 +1,96 @@
 use chrono::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(sqlx::FromRow, Debug, Serialize)]
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
 pub struct Machine {
     pub id: i32,
     pub name: String,
     pub display_name: String,
     pub active: bool,
+    /// Where bubbler listens, overriding the URL template
+    pub url: Option<String>,
+    /// Overrides MACHINE_SECRET, and is never sent back out
+    #[serde(skip)]
+    pub secret: Option<String>,
+    /// The secret before the last rotation, see `machine::signing`
+    #[serde(skip)]
+    pub previous_secret: Option<String>,
+    #[serde(skip)]
+    pub previous_secret_expires: Option<DateTime<Utc>>,
+    /// Alert when the machine stays warmer than this, see TEMP_ALERT_DURATION
+    pub max_temp: Option<f32>,
+    /// Added to our slot numbers to get the ones bubbler's /drop uses, slot status
+    /// is reported without it
+    pub slot_offset: i32,
+    /// Shown to users while the machine is down, see `Machine::maintenance`
+    pub maintenance_message: Option<String>,
+    pub maintenance_start: Option<DateTime<Utc>>,
+    /// Open ended when missing
+    pub maintenance_end: Option<DateTime<Utc>>,
 }
 
-#[derive(sqlx::FromRow, Debug, Serialize)]
+impl Machine {
+    /// The current or upcoming maintenance window, if there is one
+    #[must_use]
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        let now = Utc::now();
+        let start = self.maintenance_start?;
+        if self.maintenance_end.is_some_and(|end| end <= now) {
+            return None;
+        }
+        Some(Maintenance {
+            message: self.maintenance_message.clone(),
+            start,
+            end: self.maintenance_end,
+            active: start <= now,
+        })
+    }
+
+    /// Whether the machine is down for maintenance right now
+    #[must_use]
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance()
+            .is_some_and(|maintenance| maintenance.active)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Maintenance {
+    pub message: Option<String>,
+    pub start: DateTime<Utc>,
+    pub end: Option<DateTime<Utc>>,
+    /// False when the window is scheduled for later
+    pub active: bool,
+}
+
+impl std::fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.active {
+            write!(f, "down for maintenance")?;
+        } else {
+            write!(
+                f,
+                "going down for maintenance at {}",
+                self.start.format("%F %R UTC")
+            )?;
+        }
+        if let Some(end) = self.end {
+            write!(f, " until {}", end.format("%F %R UTC"))?;
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {message}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 pub struct Slot {
     pub machine: i32,
     pub number: i32,
-    pub item: i32,
+    /// Nothing has been put in the slot yet
+    pub item: Option<i32>,
     pub active: bool,
     pub count: Option<i32>,
 }
@@ -35,16 +112,91 @@ pub struct Drop {
     pub item: i32,
     pub item_name: String,
     pub item_price: i32,
+    pub status: String,
+    pub error: Option<String>,
+    pub hold: Option<i32>,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<DateTime<Utc>>,
 }
 
-#[derive(sqlx::FromRow, Debug, Serialize)]
+#[derive(sqlx::FromRow, Debug, Serialize, Deserialize)]
 pub struct SlotWithItem {
     pub machine: i32,
     pub number: i32,
-    pub item: i32,
+    /// Nothing has been put in the slot yet
+    pub item: Option<i32>,
     pub active: bool,
     pub count: Option<i32>,
     pub id: i32,
     pub name: String,
     pub price: i32,
 }
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct CreditHold {
+    pub id: i32,
+    pub created_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub username: String,
+    pub amount: i32,
+    pub machine: Option<i32>,
+    pub slot: Option<i32>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct CreditTransaction {
+    pub id: i32,
+    pub timestamp: DateTime<Utc>,
+    pub username: String,
+    pub kind: String,
+    pub amount: i64,
+    pub balance_before: Option<i64>,
+    pub balance_after: i64,
+    pub actor: String,
+    pub reason: Option<String>,
+    pub drop_id: Option<i32>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct IdempotencyKey {
+    pub username: String,
+    pub key: String,
+    pub created_at: DateTime<Utc>,
+    pub outcome: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct TemperatureSample {
+    pub timestamp: DateTime<Utc>,
+    pub temp: f32,
+}
+
+#[derive(sqlx::FromRow, Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i32,
+    pub timestamp: DateTime<Utc>,
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    /// JSON, as written by `db::audit::log_audit`
+    pub details: Option<String>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    /// The start of the key, for finding it and telling keys apart
+    pub prefix: String,
+    #[serde(skip)]
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub revoked_by: Option<String>,
+}
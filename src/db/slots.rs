@@ -146,3 +146,98 @@ pub async fn update_slot_item(
 
     Ok(())
 }
+
+/// All of a machine's slots, including ones without an item
+pub async fn get_machine_slots(
+    pool: &Pool<Postgres>,
+    machine_id: i32,
+) -> Result<Vec<models::Slot>, sqlx::Error> {
+    sqlx::query_as::<_, models::Slot>(
+        "SELECT machine,number,item,active,count FROM slots
+        WHERE machine = $1
+        ORDER BY number ASC",
+    )
+    .bind(machine_id)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn create_slot(
+    pool: &Pool<Postgres>,
+    slot: &models::Slot,
+) -> Result<models::Slot, sqlx::Error> {
+    sqlx::query_as::<_, models::Slot>(
+        "INSERT INTO slots(machine, number, item, active, count) VALUES ($1, $2, $3, $4, $5)
+        RETURNING machine,number,item,active,count",
+    )
+    .bind(slot.machine)
+    .bind(slot.number)
+    .bind(slot.item)
+    .bind(slot.active)
+    .bind(slot.count)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn update_slot(
+    pool: &Pool<Postgres>,
+    slot: &models::Slot,
+) -> Result<models::Slot, sqlx::Error> {
+    sqlx::query_as::<_, models::Slot>(
+        "UPDATE slots
+                SET item = $1, active = $2, count = $3
+                WHERE machine = $4 AND number = $5
+                RETURNING machine,number,item,active,count",
+    )
+    .bind(slot.item)
+    .bind(slot.active)
+    .bind(slot.count)
+    .bind(slot.machine)
+    .bind(slot.number)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn delete_slot(
+    pool: &Pool<Postgres>,
+    machine_id: i32,
+    slot_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM slots WHERE machine = $1 AND number = $2")
+        .bind(machine_id)
+        .bind(slot_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Replace every slot in a machine with `slots`, all at once
+pub async fn set_layout(
+    pool: &Pool<Postgres>,
+    machine_id: i32,
+    slots: &[models::Slot],
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query("DELETE FROM slots WHERE machine = $1")
+        .bind(machine_id)
+        .execute(&mut tx)
+        .await?;
+
+    for slot in slots {
+        sqlx::query(
+            "INSERT INTO slots(machine, number, item, active, count) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(machine_id)
+        .bind(slot.number)
+        .bind(slot.item)
+        .bind(slot.active)
+        .bind(slot.count)
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
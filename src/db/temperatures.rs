@@ -0,0 +1,27 @@
+use crate::db::models;
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+
+pub async fn log_sample(pool: &Pool<Postgres>, machine: i32, temp: f32) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO temperature_samples(machine, temp) VALUES ($1, $2)")
+        .bind(machine)
+        .bind(temp)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn get_samples(
+    pool: &Pool<Postgres>,
+    machine: i32,
+    since: DateTime<Utc>,
+) -> Result<Vec<models::TemperatureSample>, sqlx::Error> {
+    sqlx::query_as::<_, models::TemperatureSample>(
+        "SELECT timestamp, temp FROM temperature_samples WHERE machine = $1 AND timestamp >= $2 ORDER BY timestamp ASC",
+    )
+    .bind(machine)
+    .bind(since)
+    .fetch_all(pool)
+    .await
+}
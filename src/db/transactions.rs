@@ -0,0 +1,61 @@
+use crate::db::models;
+use sqlx::{Pool, Postgres};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    /// Credits spent on a drop
+    Drop,
+    /// A drink admin setting a balance by hand
+    Adjustment,
+}
+
+impl TransactionKind {
+    #[must_use]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionKind::Drop => "drop",
+            TransactionKind::Adjustment => "adjustment",
+        }
+    }
+}
+
+pub async fn log_transaction(
+    pool: &Pool<Postgres>,
+    transaction: &models::CreditTransaction,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO credit_transactions(username, kind, amount, balance_before, balance_after, actor, reason, drop_id)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+    )
+    .bind(&transaction.username)
+    .bind(&transaction.kind)
+    .bind(transaction.amount)
+    .bind(transaction.balance_before)
+    .bind(transaction.balance_after)
+    .bind(&transaction.actor)
+    .bind(&transaction.reason)
+    .bind(transaction.drop_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_user_transactions(
+    pool: &Pool<Postgres>,
+    username: &str,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<models::CreditTransaction>, sqlx::Error> {
+    sqlx::query_as::<_, models::CreditTransaction>(
+        "SELECT * FROM credit_transactions
+        WHERE username = $1
+        ORDER BY timestamp DESC, id DESC
+        LIMIT $2 OFFSET $3",
+    )
+    .bind(username)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,925 @@
+use crate::db;
+use crate::db::drops::DropStatus;
+use crate::db::holds::HoldStatus;
+use crate::db::models;
+use crate::db::transactions::TransactionKind;
+use crate::events::{Event, EventBus};
+use crate::ldap::user::LdapUser;
+use crate::ldap::{AccountBackend, Accounts, BalanceError};
+use crate::machine::{self, MachineError, Machines};
+use crate::telemetry;
+use chrono::Utc;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use queue::MachineQueues;
+
+/// How many times to retry a debit that lost a race with another balance update
+const MAX_DEBIT_ATTEMPTS: usize = 5;
+/// How long an idempotency key is remembered for, unless overridden by IDEMPOTENCY_WINDOW
+const DEFAULT_IDEMPOTENCY_WINDOW_SECS: i64 = 24 * 60 * 60;
+/// How long a drop waits for its machine, unless overridden by DROP_QUEUE_TIMEOUT
+const DEFAULT_QUEUE_TIMEOUT_SECS: u64 = 30;
+/// How much longer than the queue timeout a drop may take before its idempotency
+/// key is considered abandoned, enough for the machine and LDAP to answer
+const IN_PROGRESS_MARGIN_SECS: u64 = 60;
+/// Longest idempotency key that can be stored
+pub const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+/// Bumped whenever `Replay` changes shape
+const REPLAY_VERSION: u32 = 1;
+
+/// A request to drop an item, independent of the front end it came in through
+#[derive(Debug, Clone)]
+pub struct DropRequest {
+    pub username: String,
+    pub machine: String,
+    pub slot: i32,
+    /// A client-chosen key, so a retried request replays the original result
+    /// instead of dispensing again
+    pub idempotency_key: Option<String>,
+}
+
+/// Everything a front end needs to tell the user about a successful drop
+#[derive(Debug)]
+pub struct DropOutcome {
+    pub machine: String,
+    pub machine_display_name: String,
+    pub slot: i32,
+    /// The name of the item that was dropped
+    pub item: String,
+    pub new_balance: i64,
+    /// How many drops were ahead of this one on the machine
+    pub queue_position: usize,
+}
+
+#[derive(Debug)]
+pub enum DropError {
+    InvalidMachine(String),
+    InvalidSlot {
+        machine: String,
+        slot: i32,
+    },
+    MachineOffline(Box<models::Machine>),
+    /// The machine is being serviced, see `models::Machine::maintenance`
+    Maintenance(Box<models::Machine>),
+    SlotEmpty {
+        machine: Box<models::Machine>,
+        slot: i32,
+    },
+    UserNotFound(String),
+    InsufficientBalance {
+        balance: i64,
+        price: i32,
+    },
+    ConnectionFailed(Box<models::Machine>),
+    TimedOut {
+        machine: Box<models::Machine>,
+        incident: Option<i32>,
+    },
+    /// Waited too long behind other drops on the machine
+    MachineBusy {
+        machine: Box<models::Machine>,
+        position: usize,
+    },
+    MachineError {
+        status: u16,
+        message: String,
+        incident: Option<i32>,
+    },
+    /// A request with the same idempotency key has not finished yet
+    InProgress,
+    /// The idempotency key was already used to drop from a different slot
+    KeyReused,
+    Unknown {
+        message: String,
+        incident: Option<i32>,
+    },
+}
+
+impl DropError {
+    /// The incident logged for this drop, if the machine may have actuated
+    #[must_use]
+    pub fn incident(&self) -> Option<i32> {
+        match self {
+            DropError::TimedOut { incident, .. }
+            | DropError::MachineError { incident, .. }
+            | DropError::Unknown { incident, .. } => *incident,
+            _ => None,
+        }
+    }
+
+    /// A short name for metrics
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DropError::InvalidMachine(_) => "invalid_machine",
+            DropError::InvalidSlot { .. } => "invalid_slot",
+            DropError::MachineOffline(_) => "machine_offline",
+            DropError::Maintenance(_) => "maintenance",
+            DropError::SlotEmpty { .. } => "slot_empty",
+            DropError::UserNotFound(_) => "user_not_found",
+            DropError::InsufficientBalance { .. } => "insufficient_balance",
+            DropError::ConnectionFailed(_) => "connection_failed",
+            DropError::TimedOut { .. } => "timed_out",
+            DropError::MachineBusy { .. } => "machine_busy",
+            DropError::MachineError { .. } => "machine_error",
+            DropError::InProgress => "in_progress",
+            DropError::KeyReused => "key_reused",
+            DropError::Unknown { .. } => "unknown",
+        }
+    }
+
+    fn unknown(message: impl ToString) -> Self {
+        DropError::Unknown {
+            message: message.to_string(),
+            incident: None,
+        }
+    }
+
+    fn with_incident(mut self, id: i32) -> Self {
+        match &mut self {
+            DropError::TimedOut { incident, .. }
+            | DropError::MachineError { incident, .. }
+            | DropError::Unknown { incident, .. } => *incident = Some(id),
+            _ => {}
+        }
+        self
+    }
+}
+
+impl Error for DropError {}
+
+impl std::fmt::Display for DropError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DropError::InvalidMachine(name) => write!(f, "{name} is not a valid machine"),
+            DropError::InvalidSlot { machine, slot } => {
+                write!(f, "machine {machine} does not have a slot with id {slot}")
+            }
+            DropError::MachineOffline(machine) => {
+                write!(f, "machine {} is not online", machine.name)
+            }
+            DropError::Maintenance(machine) => match machine.maintenance() {
+                Some(maintenance) => write!(f, "machine {} is {maintenance}", machine.name),
+                None => write!(f, "machine {} is down for maintenance", machine.name),
+            },
+            DropError::SlotEmpty { machine, slot } => {
+                write!(f, "machine {} slot {slot} is empty", machine.name)
+            }
+            DropError::UserNotFound(uid) => write!(f, "could not find user {uid}"),
+            DropError::InsufficientBalance { balance, price } => {
+                write!(
+                    f,
+                    "insufficient drink balance (has {balance}, needs {price})"
+                )
+            }
+            DropError::ConnectionFailed(machine) => {
+                write!(f, "could not connect to machine {}", machine.name)
+            }
+            DropError::TimedOut { machine, .. } => {
+                write!(f, "machine {} timed out", machine.name)
+            }
+            DropError::MachineBusy { machine, position } => {
+                write!(f, "machine {} is busy ({position} ahead)", machine.name)
+            }
+            DropError::MachineError {
+                status, message, ..
+            } => {
+                write!(f, "machine returned {status}: {message}")
+            }
+            DropError::InProgress => write!(f, "a drop with this key is already in progress"),
+            DropError::KeyReused => {
+                write!(f, "this key was already used for a different drop")
+            }
+            DropError::Unknown { message, .. } => write!(f, "unknown error: {message}"),
+        }
+    }
+}
+
+/// What's remembered against an idempotency key, enough to answer a retry
+///
+/// This holds plain values rather than the models, so that records stored before
+/// an upgrade can still be read after it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Replay {
+    version: u32,
+    machine: String,
+    slot: i32,
+    result: ReplayResult,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ReplayResult {
+    Dropped {
+        machine_display_name: String,
+        item: String,
+        new_balance: i64,
+        queue_position: usize,
+    },
+    TimedOut {
+        incident: Option<i32>,
+    },
+    MachineError {
+        status: u16,
+        message: String,
+        incident: Option<i32>,
+    },
+    Unknown {
+        message: String,
+        incident: Option<i32>,
+    },
+}
+
+impl Replay {
+    /// The record for a result, if the machine may have dispensed
+    ///
+    /// Anything that failed before the drop was sent is safe to try again, so it
+    /// isn't remembered.
+    fn new(request: &DropRequest, result: &Result<DropOutcome, DropError>) -> Option<Self> {
+        let result = match result {
+            Ok(outcome) => ReplayResult::Dropped {
+                machine_display_name: outcome.machine_display_name.clone(),
+                item: outcome.item.clone(),
+                new_balance: outcome.new_balance,
+                queue_position: outcome.queue_position,
+            },
+            Err(DropError::TimedOut { incident, .. }) => ReplayResult::TimedOut {
+                incident: *incident,
+            },
+            Err(DropError::MachineError {
+                status,
+                message,
+                incident,
+            }) => ReplayResult::MachineError {
+                status: *status,
+                message: message.clone(),
+                incident: *incident,
+            },
+            // Unknown errors only come from the machine once they have an incident
+            Err(DropError::Unknown {
+                message,
+                incident: incident @ Some(_),
+            }) => ReplayResult::Unknown {
+                message: message.clone(),
+                incident: *incident,
+            },
+            Err(_) => return None,
+        };
+        Some(Replay {
+            version: REPLAY_VERSION,
+            machine: request.machine.clone(),
+            slot: request.slot,
+            result,
+        })
+    }
+}
+
+/// Whether a slot should be considered empty, given the machine's reported state
+///
+/// Slots with a count (such as snack) are tracked in the database, all other
+/// slots rely on the stocked sensor reported by the machine.
+#[must_use]
+pub fn is_slot_empty(
+    slot: &models::SlotWithItem,
+    state: Option<&machine::MachineResponse>,
+) -> bool {
+    match slot.count {
+        Some(count) => count < 1,
+        None => match state.and_then(|state| {
+            state
+                .slots
+                .iter()
+                .find(|slot_state| slot_state.number == slot.number)
+        }) {
+            Some(slot_state) => !slot_state.stocked,
+            None => true,
+        },
+    }
+}
+
+/// The single drop pipeline shared by every front end (HTTP, SMS, ...)
+#[derive(Clone)]
+pub struct DropService {
+    pool: Arc<Pool<Postgres>>,
+    accounts: Accounts,
+    machines: Machines,
+    events: EventBus,
+    idempotency_window: i64,
+    queues: Arc<MachineQueues>,
+    queue_timeout: Duration,
+}
+
+impl DropService {
+    #[must_use]
+    pub fn new(
+        pool: Arc<Pool<Postgres>>,
+        accounts: Accounts,
+        machines: Machines,
+        events: EventBus,
+    ) -> Self {
+        let idempotency_window = env::var("IDEMPOTENCY_WINDOW")
+            .ok()
+            .and_then(|window| window.parse().ok())
+            .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW_SECS);
+        let queue_timeout = env::var("DROP_QUEUE_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+            .unwrap_or(DEFAULT_QUEUE_TIMEOUT_SECS);
+
+        DropService {
+            pool,
+            accounts,
+            machines,
+            events,
+            idempotency_window,
+            queues: Arc::new(MachineQueues::new()),
+            queue_timeout: Duration::from_secs(queue_timeout),
+        }
+    }
+
+    pub async fn drop(&self, request: &DropRequest) -> Result<DropOutcome, DropError> {
+        let key = match &request.idempotency_key {
+            Some(key) => key,
+            None => return self.execute(request).await,
+        };
+        let user_id = &request.username;
+
+        let stale = (self.queue_timeout.as_secs() + IN_PROGRESS_MARGIN_SECS) as i64;
+        match db::idempotency::claim_key(&self.pool, user_id, key, self.idempotency_window, stale)
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(existing)) => {
+                return match existing.outcome {
+                    Some(outcome) => self.replay(request, key, &outcome).await,
+                    None => {
+                        warn!(
+                            "Rejecting request from {} to drop a drink, idempotency key {} is already in progress",
+                            user_id, key
+                        );
+                        Err(DropError::InProgress)
+                    }
+                };
+            }
+            Err(e) => {
+                error!(
+                    "Error claiming idempotency key {} for {}: {}",
+                    key, user_id, e
+                );
+                return Err(DropError::unknown(e));
+            }
+        }
+
+        // Carry on without the caller if they hang up, so the claim still gets its
+        // outcome instead of being left in progress
+        let service = self.clone();
+        let request = request.clone();
+        let key = key.clone();
+        tokio::spawn(async move { service.execute_claimed(&request, &key).await })
+            .await
+            .unwrap_or_else(|e| Err(DropError::unknown(e)))
+    }
+
+    /// Drop for a request whose idempotency key was just claimed, then remember or
+    /// release the key
+    async fn execute_claimed(
+        &self,
+        request: &DropRequest,
+        key: &str,
+    ) -> Result<DropOutcome, DropError> {
+        let user_id = &request.username;
+        let result = self.execute(request).await;
+
+        // Only results where the machine may have dispensed are remembered, anything
+        // rejected up front is safe to try again
+        let replay = Replay::new(request, &result).map(|replay| serde_json::to_string(&replay));
+        let stored = match replay {
+            Some(Ok(outcome)) => {
+                db::idempotency::complete_key(&self.pool, user_id, key, &outcome).await
+            }
+            Some(Err(e)) => {
+                error!("Error serializing drop outcome for {}: {}", user_id, e);
+                db::idempotency::release_key(&self.pool, user_id, key).await
+            }
+            None => db::idempotency::release_key(&self.pool, user_id, key).await,
+        };
+        if let Err(e) = stored {
+            error!(
+                "Error storing idempotency key {} for {}: {}",
+                key, user_id, e
+            );
+        }
+
+        result
+    }
+
+    /// Answer a retry with the result remembered for its idempotency key
+    async fn replay(
+        &self,
+        request: &DropRequest,
+        key: &str,
+        outcome: &str,
+    ) -> Result<DropOutcome, DropError> {
+        let user_id = &request.username;
+        let replay = match serde_json::from_str::<Replay>(outcome) {
+            Ok(replay) if replay.version == REPLAY_VERSION => replay,
+            Ok(replay) => {
+                error!(
+                    "Can't replay idempotency key {} for {}, record is version {}",
+                    key, user_id, replay.version
+                );
+                return Err(DropError::unknown("the original result can't be replayed"));
+            }
+            Err(e) => {
+                error!(
+                    "Can't replay idempotency key {} for {}: {}",
+                    key, user_id, e
+                );
+                return Err(DropError::unknown("the original result can't be replayed"));
+            }
+        };
+        if replay.machine != request.machine || replay.slot != request.slot {
+            warn!(
+                "Rejecting request from {} to drop a drink, idempotency key {} was used for {} slot {}",
+                user_id, key, replay.machine, replay.slot
+            );
+            return Err(DropError::KeyReused);
+        }
+
+        info!(
+            "Replaying drop for {} with idempotency key {}",
+            user_id, key
+        );
+        match replay.result {
+            ReplayResult::Dropped {
+                machine_display_name,
+                item,
+                new_balance,
+                queue_position,
+            } => Ok(DropOutcome {
+                machine: replay.machine,
+                machine_display_name,
+                slot: replay.slot,
+                item,
+                new_balance,
+                queue_position,
+            }),
+            ReplayResult::TimedOut { incident } => {
+                match db::machines::get_any_machine(&self.pool, &replay.machine).await {
+                    Ok(machine) => Err(DropError::TimedOut {
+                        machine: Box::new(machine),
+                        incident,
+                    }),
+                    Err(_) => Err(DropError::Unknown {
+                        message: format!("machine {} timed out", replay.machine),
+                        incident,
+                    }),
+                }
+            }
+            ReplayResult::MachineError {
+                status,
+                message,
+                incident,
+            } => Err(DropError::MachineError {
+                status,
+                message,
+                incident,
+            }),
+            ReplayResult::Unknown { message, incident } => {
+                Err(DropError::Unknown { message, incident })
+            }
+        }
+    }
+
+    async fn execute(&self, request: &DropRequest) -> Result<DropOutcome, DropError> {
+        let result = self.run(request).await;
+
+        // Names and numbers that don't exist came from the user, so they don't
+        // become labels. The slot is only known to exist once it has been looked
+        // up, which maintenance and a busy machine turn the drop away before.
+        let (machine, slot) = match &result {
+            Err(DropError::InvalidMachine(_)) => ("invalid", String::from("invalid")),
+            Err(DropError::InvalidSlot { .. }) => {
+                (request.machine.as_str(), String::from("invalid"))
+            }
+            Ok(_)
+            | Err(
+                DropError::MachineOffline(_)
+                | DropError::SlotEmpty { .. }
+                | DropError::UserNotFound(_)
+                | DropError::InsufficientBalance { .. }
+                | DropError::ConnectionFailed(_)
+                | DropError::TimedOut { .. }
+                | DropError::MachineError { .. }
+                | DropError::Unknown { .. },
+            ) => (request.machine.as_str(), request.slot.to_string()),
+            Err(_) => (request.machine.as_str(), String::from("unknown")),
+        };
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(e) => e.kind(),
+        };
+        telemetry::record_drop(machine, slot, outcome);
+        result
+    }
+
+    async fn run(&self, request: &DropRequest) -> Result<DropOutcome, DropError> {
+        let user_id = &request.username;
+
+        debug!("Fetching database info for drop request by {}", user_id);
+        let machine = match db::machines::get_machine(&self.pool, &request.machine).await {
+            Ok(machine) => machine,
+            Err(_) => {
+                warn!(
+                    "Rejecting request from {} to drop a drink, {} is not a valid machine",
+                    user_id, request.machine
+                );
+                return Err(DropError::InvalidMachine(request.machine.clone()));
+            }
+        };
+
+        if machine.in_maintenance() {
+            warn!(
+                "Rejecting request from {} to drop a drink, machine {} is down for maintenance",
+                user_id, machine.name
+            );
+            return Err(DropError::Maintenance(Box::new(machine)));
+        }
+
+        // Wait our turn before reading slot state, so a drop ahead of us in the queue
+        // is reflected in what we see
+        debug!("Waiting for machine {} for {}", machine.name, user_id);
+        let ticket = match self.queues.acquire(machine.id, self.queue_timeout).await {
+            Ok(ticket) => ticket,
+            Err(timeout) => {
+                warn!(
+                    "Rejecting request from {} to drop a drink, machine {} is busy ({} ahead)",
+                    user_id, machine.name, timeout.position
+                );
+                return Err(DropError::MachineBusy {
+                    machine: Box::new(machine),
+                    position: timeout.position,
+                });
+            }
+        };
+        let queue_position = ticket.position;
+
+        let slot = match db::slots::get_slot_with_item(&self.pool, machine.id, request.slot).await {
+            Ok(slot) => slot,
+            Err(_) => {
+                warn!(
+                    "Rejecting request from {} to drop a drink, machine {} does not have a slot with id {}",
+                    user_id, machine.name, request.slot
+                );
+                return Err(DropError::InvalidSlot {
+                    machine: machine.name,
+                    slot: request.slot,
+                });
+            }
+        };
+
+        debug!("Checking machine {} status for {}", machine.name, user_id);
+        let machine_status = match self.machines.get_status(&machine).await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!(
+                    "Rejecting request from {} to drop a drink, machine {} is not online: {}",
+                    user_id, machine.name, e
+                );
+                return Err(DropError::MachineOffline(Box::new(machine)));
+            }
+        };
+
+        if !slot.active || is_slot_empty(&slot, Some(&machine_status)) {
+            warn!(
+                "Rejecting request from {} to drop a drink, machine {} slot {} is empty",
+                user_id, machine.name, slot.number
+            );
+            return Err(DropError::SlotEmpty {
+                machine: Box::new(machine),
+                slot: slot.number,
+            });
+        }
+
+        debug!("Checking drink credits for {}", user_id);
+        let user = match self.accounts.get_user(user_id).await {
+            Some(user) => user,
+            None => return Err(DropError::UserNotFound(user_id.clone())),
+        };
+        let balance = user.drinkBalance.unwrap_or(0);
+
+        debug!("Placing a hold of {} credits for {}", slot.price, user_id);
+        let hold = match db::holds::place_hold(
+            &self.pool,
+            user_id,
+            balance,
+            slot.price,
+            machine.id,
+            slot.number,
+        )
+        .await
+        {
+            Ok(Some(hold)) => hold,
+            Ok(None) => {
+                warn!(
+                    "Rejecting request from {} to drop a drink, insufficient drink balance for {} (has {}, needs {})",
+                    user_id, slot.name, balance, slot.price,
+                );
+                return Err(DropError::InsufficientBalance {
+                    balance,
+                    price: slot.price,
+                });
+            }
+            Err(e) => {
+                error!("Error placing credit hold for {}: {}", user_id, e);
+                return Err(DropError::unknown(e));
+            }
+        };
+
+        let dispensed = self.dispense(user_id, &machine, &slot).await;
+        // The machine is free for the next drop as soon as it has answered
+        std::mem::drop(ticket);
+        if let Err(e) = dispensed {
+            let status = match &e {
+                DropError::MachineError { .. } => Some(DropStatus::Failed),
+                DropError::TimedOut { .. } | DropError::Unknown { .. } => Some(DropStatus::Unknown),
+                _ => None,
+            };
+            // If we can't tell whether the item came out, keep the credits reserved
+            // until a drink admin resolves the incident
+            if status != Some(DropStatus::Unknown) {
+                self.resolve_hold(hold, HoldStatus::Released, None).await;
+            }
+            if let Some(status) = status {
+                if let Some(incident) = self
+                    .log_incident(user_id, &machine, &slot, hold, status, &e)
+                    .await
+                {
+                    return Err(e.with_incident(incident));
+                }
+            }
+            return Err(e);
+        }
+
+        debug!("Committing hold {} for {}", hold, user_id);
+        let debit = debit(self.accounts.as_ref(), user, slot.price).await;
+        match &debit {
+            Ok(_) => self.resolve_hold(hold, HoldStatus::Committed, None).await,
+            Err(e) => {
+                // The item is already out of the machine, so flag the hold for a drink
+                // admin rather than failing the drop
+                error!(
+                    "Error debiting {} credits from {} after drop, hold {} is unreconciled: {}",
+                    slot.price, user_id, hold, e
+                );
+                self.resolve_hold(hold, HoldStatus::Unreconciled, Some(&e.to_string()))
+                    .await;
+            }
+        }
+
+        if let Some(count) = slot.count {
+            self.decrement_slot(user_id, &machine, &slot, count).await;
+        }
+
+        let drop = models::Drop {
+            id: 0,                 // placeholder,
+            timestamp: Utc::now(), // placeholder
+            username: user_id.clone(),
+            machine: machine.id,
+            slot: slot.number,
+            item: slot.id,
+            item_name: slot.name.clone(),
+            item_price: slot.price,
+            status: DropStatus::Success.as_str().to_owned(),
+            error: None,
+            hold: Some(hold),
+            resolved_by: None,
+            resolved_at: None,
+        };
+        let drop_id = match db::drops::log_drop(&self.pool, &drop).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                warn!("Error logging drop: {e}");
+                None
+            }
+        };
+
+        let new_balance = match debit {
+            Ok(new_balance) => {
+                let transaction = models::CreditTransaction {
+                    id: 0,                 // placeholder
+                    timestamp: Utc::now(), // placeholder
+                    username: user_id.clone(),
+                    kind: TransactionKind::Drop.as_str().to_owned(),
+                    amount: -i64::from(slot.price),
+                    balance_before: Some(new_balance + i64::from(slot.price)),
+                    balance_after: new_balance,
+                    actor: user_id.clone(),
+                    reason: Some(format!(
+                        "Dropped {} from {} slot {}",
+                        slot.name, machine.name, slot.number
+                    )),
+                    drop_id,
+                };
+                if let Err(e) = db::transactions::log_transaction(&self.pool, &transaction).await {
+                    warn!("Error logging credit transaction: {e}");
+                }
+                new_balance
+            }
+            Err(_) => balance - i64::from(slot.price),
+        };
+
+        info!("Successfully dropped {} for {}", slot.name, user_id);
+        self.events.publish(Event::Drop {
+            machine: machine.name.clone(),
+            slot: slot.number,
+            item: slot.name.clone(),
+        });
+        Ok(DropOutcome {
+            machine: machine.name,
+            machine_display_name: machine.display_name,
+            slot: slot.number,
+            item: slot.name,
+            new_balance,
+            queue_position,
+        })
+    }
+
+    async fn dispense(
+        &self,
+        user_id: &str,
+        machine: &models::Machine,
+        slot: &models::SlotWithItem,
+    ) -> Result<(), DropError> {
+        debug!(
+            "Sending drop request for machine {} slot {} by {}",
+            machine.name, slot.number, user_id
+        );
+        match self.machines.drop_slot(machine, slot.number).await {
+            Ok(()) => Ok(()),
+            Err(MachineError::Connect(e)) => {
+                error!(
+                    "Error dropping drink for {}, could not connect to machine {}: {}",
+                    user_id, machine.name, e
+                );
+                Err(DropError::ConnectionFailed(Box::new(machine.clone())))
+            }
+            Err(MachineError::Timeout) => {
+                error!(
+                    "Error dropping drink for {}, machine {} timed out",
+                    user_id, machine.name
+                );
+                Err(DropError::TimedOut {
+                    machine: Box::new(machine.clone()),
+                    incident: None,
+                })
+            }
+            Err(MachineError::Status { status, message }) => {
+                error!(
+                    "Error dropping drink for {}, an error occured dropping a drink from machine {} slot {}: {}",
+                    user_id, machine.name, slot.number, message
+                );
+                Err(DropError::MachineError {
+                    status,
+                    message,
+                    incident: None,
+                })
+            }
+            Err(MachineError::Other(e)) => {
+                error!(
+                    "Error dropping drink for {}, an unknown error occured dropping a drink from machine {} slot {}: {}",
+                    user_id, machine.name, slot.number, e
+                );
+                Err(DropError::unknown(e))
+            }
+        }
+    }
+
+    async fn log_incident(
+        &self,
+        user_id: &str,
+        machine: &models::Machine,
+        slot: &models::SlotWithItem,
+        hold: i32,
+        status: DropStatus,
+        error: &DropError,
+    ) -> Option<i32> {
+        let drop = models::Drop {
+            id: 0,                 // placeholder,
+            timestamp: Utc::now(), // placeholder
+            username: user_id.to_owned(),
+            machine: machine.id,
+            slot: slot.number,
+            item: slot.id,
+            item_name: slot.name.clone(),
+            item_price: slot.price,
+            status: status.as_str().to_owned(),
+            error: Some(error.to_string()),
+            hold: Some(hold),
+            resolved_by: None,
+            resolved_at: None,
+        };
+        match db::drops::log_drop(&self.pool, &drop).await {
+            Ok(id) => {
+                warn!(
+                    "Logged drop incident {} ({}) for {} on machine {} slot {}",
+                    id,
+                    status.as_str(),
+                    user_id,
+                    machine.name,
+                    slot.number
+                );
+                Some(id)
+            }
+            Err(e) => {
+                error!("Error logging drop incident for {}: {}", user_id, e);
+                None
+            }
+        }
+    }
+
+    async fn resolve_hold(&self, hold: i32, status: HoldStatus, error: Option<&str>) {
+        if let Err(e) = db::holds::resolve_hold(&self.pool, hold, status, error).await {
+            error!("Error marking hold {} as {}: {}", hold, status.as_str(), e);
+        }
+    }
+
+    async fn decrement_slot(
+        &self,
+        user_id: &str,
+        machine: &models::Machine,
+        slot: &models::SlotWithItem,
+        count: i32,
+    ) {
+        if db::slots::update_slot_count(&self.pool, machine.id, slot.number, count - 1)
+            .await
+            .is_ok()
+        {
+            self.events.publish(Event::SlotCount {
+                machine: machine.name.clone(),
+                slot: slot.number,
+                count: count - 1,
+            });
+        } else {
+            error!(
+                "Error updating db after drop for {}, could not change machine {} slot {} count {}",
+                user_id,
+                machine.name,
+                slot.number,
+                count - 1
+            );
+        }
+        if count == 1
+            && db::slots::update_slot_active(&self.pool, machine.id, slot.number, false)
+                .await
+                .is_err()
+        {
+            error!(
+                "Error updating db after drop for {}, could not change machine {} slot {} active {}",
+                user_id, machine.name, slot.number, false
+            );
+        }
+    }
+}
+
+/// Debit `amount` credits from `user`, re-reading the balance and retrying if it
+/// changed underneath us
+async fn debit(
+    accounts: &dyn AccountBackend,
+    user: LdapUser,
+    amount: i32,
+) -> Result<i64, BalanceError> {
+    let mut user = user;
+    let mut attempt = 1;
+    loop {
+        let new_balance = user.drinkBalance.unwrap_or(0) - i64::from(amount);
+        match accounts
+            .update_balance(&user.dn, user.drinkBalance, new_balance)
+            .await
+        {
+            Ok(()) => return Ok(new_balance),
+            Err(BalanceError::Conflict) if attempt < MAX_DEBIT_ATTEMPTS => {
+                warn!(
+                    "drinkBalance for {} changed during debit, retrying (attempt {})",
+                    user.uid, attempt
+                );
+                attempt += 1;
+                user = accounts
+                    .get_user(&user.uid)
+                    .await
+                    .ok_or(BalanceError::Conflict)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+pub mod hold;
+pub mod incident;
+pub mod queue;
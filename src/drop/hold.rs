@@ -0,0 +1,142 @@
+use super::{debit, DropService};
+use crate::db;
+use crate::db::holds::HoldStatus;
+use crate::db::models;
+use crate::db::transactions::TransactionKind;
+use crate::ldap::BalanceError;
+use chrono::Utc;
+use log::{info, warn};
+use std::error::Error;
+
+#[derive(Debug)]
+pub enum HoldError {
+    NotFound(i32),
+    /// Only holds whose debit failed are left for a drink admin to settle
+    NotUnreconciled {
+        id: i32,
+        status: String,
+    },
+    UserNotFound(String),
+    BalanceError(BalanceError),
+    SqlxError(sqlx::Error),
+}
+
+impl Error for HoldError {}
+
+impl std::fmt::Display for HoldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HoldError::NotFound(id) => write!(f, "no hold with id {id}"),
+            HoldError::NotUnreconciled { id, status } => {
+                write!(
+                    f,
+                    "hold {id} is {status}, only unreconciled holds can be settled"
+                )
+            }
+            HoldError::UserNotFound(uid) => write!(f, "could not find user {uid}"),
+            HoldError::BalanceError(be) => write!(f, "{be}"),
+            HoldError::SqlxError(se) => write!(f, "Database Error: {se}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for HoldError {
+    fn from(e: sqlx::Error) -> Self {
+        HoldError::SqlxError(e)
+    }
+}
+
+impl DropService {
+    /// Settle a hold whose debit failed after the item came out, either by charging
+    /// the user now (`Committed`) or forgiving it (`Released`)
+    pub async fn settle_hold(
+        &self,
+        id: i32,
+        status: HoldStatus,
+        actor: &str,
+    ) -> Result<models::CreditHold, HoldError> {
+        let hold = match db::holds::get_hold(&self.pool, id).await {
+            Ok(hold) => hold,
+            Err(sqlx::Error::RowNotFound) => return Err(HoldError::NotFound(id)),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Claim the hold before charging, so settling it twice can't charge twice
+        let note = format!(
+            "{} (resolved by {})",
+            hold.error.as_deref().unwrap_or_default(),
+            actor
+        );
+        let settled = match db::holds::settle_hold(&self.pool, hold.id, status, Some(&note)).await?
+        {
+            Some(settled) => settled,
+            None => {
+                let current = db::holds::get_hold(&self.pool, hold.id).await?;
+                return Err(HoldError::NotUnreconciled {
+                    id,
+                    status: current.status,
+                });
+            }
+        };
+
+        if status == HoldStatus::Committed {
+            if let Err(e) = self.charge_hold(&hold, actor).await {
+                // Nobody was charged, so leave it for another try
+                if let Err(reopen) =
+                    db::holds::reopen_hold(&self.pool, hold.id, status, hold.error.as_deref()).await
+                {
+                    warn!("Error reopening hold {}: {}", hold.id, reopen);
+                }
+                return Err(e);
+            }
+        }
+
+        info!(
+            "{} settled hold {} for {} as {}",
+            actor,
+            hold.id,
+            hold.username,
+            status.as_str()
+        );
+        Ok(settled)
+    }
+
+    /// Debit the user for a hold, and record it against the drop it was for
+    async fn charge_hold(&self, hold: &models::CreditHold, actor: &str) -> Result<(), HoldError> {
+        let user = self
+            .accounts
+            .get_user(&hold.username)
+            .await
+            .ok_or_else(|| HoldError::UserNotFound(hold.username.clone()))?;
+        let new_balance = debit(self.accounts.as_ref(), user, hold.amount)
+            .await
+            .map_err(HoldError::BalanceError)?;
+
+        let drop = match db::drops::get_drop_for_hold(&self.pool, hold.id).await {
+            Ok(drop) => drop,
+            Err(e) => {
+                warn!("Error finding the drop for hold {}: {}", hold.id, e);
+                None
+            }
+        };
+        let transaction = models::CreditTransaction {
+            id: 0,                 // placeholder
+            timestamp: Utc::now(), // placeholder
+            username: hold.username.clone(),
+            kind: TransactionKind::Drop.as_str().to_owned(),
+            amount: -i64::from(hold.amount),
+            balance_before: Some(new_balance + i64::from(hold.amount)),
+            balance_after: new_balance,
+            actor: actor.to_owned(),
+            reason: Some(match &drop {
+                Some(drop) => format!("Settled hold {}: dropped {}", hold.id, drop.item_name),
+                None => format!("Settled hold {}", hold.id),
+            }),
+            drop_id: drop.map(|drop| drop.id),
+        };
+        if let Err(e) = db::transactions::log_transaction(&self.pool, &transaction).await {
+            warn!("Error logging credit transaction: {e}");
+        }
+        Ok(())
+    }
+}
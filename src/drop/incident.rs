@@ -0,0 +1,183 @@
+use super::{debit, DropService};
+use crate::db;
+use crate::db::drops::DropStatus;
+use crate::db::holds::HoldStatus;
+use crate::db::models;
+use crate::db::transactions::TransactionKind;
+use crate::ldap::BalanceError;
+use chrono::Utc;
+use log::{info, warn};
+use std::error::Error;
+
+/// What to tell a user whose drop was logged as an incident
+#[must_use]
+pub fn incident_message(incident: i32) -> String {
+    format!("This was logged as incident #{incident}, a drink admin will sort out your credits")
+}
+
+/// What a drink admin decided happened during a failed or ambiguous drop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The item never came out, so the user isn't charged
+    Refund,
+    /// The item came out, so the user is charged after all
+    Confirm,
+}
+
+#[derive(Debug)]
+pub enum IncidentError {
+    NotFound(i32),
+    /// The drop isn't failed or unknown, so there is nothing to resolve
+    NotAnIncident {
+        id: i32,
+        status: String,
+    },
+    UserNotFound(String),
+    BalanceError(BalanceError),
+    SqlxError(sqlx::Error),
+}
+
+impl Error for IncidentError {}
+
+impl std::fmt::Display for IncidentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IncidentError::NotFound(id) => write!(f, "no drop with id {id}"),
+            IncidentError::NotAnIncident { id, status } => {
+                write!(f, "drop {id} is {status}, not an incident")
+            }
+            IncidentError::UserNotFound(uid) => write!(f, "could not find user {uid}"),
+            IncidentError::BalanceError(be) => write!(f, "{be}"),
+            IncidentError::SqlxError(se) => write!(f, "Database Error: {se}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for IncidentError {
+    fn from(e: sqlx::Error) -> Self {
+        IncidentError::SqlxError(e)
+    }
+}
+
+impl DropService {
+    pub async fn resolve_incident(
+        &self,
+        id: i32,
+        resolution: Resolution,
+        actor: &str,
+    ) -> Result<models::Drop, IncidentError> {
+        let drop = match db::drops::get_drop(&self.pool, id).await {
+            Ok(drop) => drop,
+            Err(sqlx::Error::RowNotFound) => return Err(IncidentError::NotFound(id)),
+            Err(e) => return Err(e.into()),
+        };
+        if drop.status != DropStatus::Failed.as_str() && drop.status != DropStatus::Unknown.as_str()
+        {
+            return Err(IncidentError::NotAnIncident {
+                id,
+                status: drop.status,
+            });
+        }
+
+        // Claim the incident before touching any credits, so a second resolution of
+        // the same incident finds it already resolved instead of charging again
+        let status = match resolution {
+            Resolution::Refund => DropStatus::Refunded,
+            Resolution::Confirm => DropStatus::Confirmed,
+        };
+        let resolved = match db::drops::resolve_drop(
+            &self.pool,
+            drop.id,
+            &drop.status,
+            status,
+            actor,
+        )
+        .await?
+        {
+            Some(resolved) => resolved,
+            None => {
+                let current = db::drops::get_drop(&self.pool, drop.id).await?;
+                return Err(IncidentError::NotAnIncident {
+                    id,
+                    status: current.status,
+                });
+            }
+        };
+
+        match resolution {
+            Resolution::Refund => {
+                if let Some(hold) = self.outstanding_hold(&drop).await? {
+                    self.resolve_hold(hold, HoldStatus::Released, None).await;
+                }
+            }
+            Resolution::Confirm => {
+                let new_balance = match self.charge(&drop).await {
+                    Ok(new_balance) => new_balance,
+                    Err(e) => {
+                        // Nobody was charged, so leave it for another try
+                        if let Err(reopen) =
+                            db::drops::reopen_drop(&self.pool, drop.id, status, &drop.status).await
+                        {
+                            warn!("Error reopening drop incident {}: {}", drop.id, reopen);
+                        }
+                        return Err(e);
+                    }
+                };
+                if let Some(hold) = self.outstanding_hold(&drop).await? {
+                    self.resolve_hold(hold, HoldStatus::Committed, None).await;
+                }
+
+                let transaction = models::CreditTransaction {
+                    id: 0,                 // placeholder
+                    timestamp: Utc::now(), // placeholder
+                    username: drop.username.clone(),
+                    kind: TransactionKind::Drop.as_str().to_owned(),
+                    amount: -i64::from(drop.item_price),
+                    balance_before: Some(new_balance + i64::from(drop.item_price)),
+                    balance_after: new_balance,
+                    actor: actor.to_owned(),
+                    reason: Some(format!(
+                        "Confirmed incident {}: dropped {}",
+                        drop.id, drop.item_name
+                    )),
+                    drop_id: Some(drop.id),
+                };
+                if let Err(e) = db::transactions::log_transaction(&self.pool, &transaction).await {
+                    warn!("Error logging credit transaction: {e}");
+                }
+            }
+        }
+
+        info!(
+            "{} resolved drop incident {} for {} as {}",
+            actor,
+            drop.id,
+            drop.username,
+            status.as_str()
+        );
+        Ok(resolved)
+    }
+
+    /// Debit the user for a drop that turned out to have dispensed
+    async fn charge(&self, drop: &models::Drop) -> Result<i64, IncidentError> {
+        let user = self
+            .accounts
+            .get_user(&drop.username)
+            .await
+            .ok_or_else(|| IncidentError::UserNotFound(drop.username.clone()))?;
+        debit(self.accounts.as_ref(), user, drop.item_price)
+            .await
+            .map_err(IncidentError::BalanceError)
+    }
+
+    /// The drop's hold, if it is still reserving credits
+    async fn outstanding_hold(&self, drop: &models::Drop) -> Result<Option<i32>, sqlx::Error> {
+        match drop.hold {
+            Some(hold) => {
+                let hold = db::holds::get_hold(&self.pool, hold).await?;
+                Ok((hold.status == HoldStatus::Held.as_str()).then_some(hold.id))
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+
+/// Per-machine FIFO queues, so only one drop is sent to a machine at a time
+#[derive(Default)]
+pub struct MachineQueues {
+    queues: Mutex<HashMap<i32, Arc<MachineQueue>>>,
+}
+
+#[derive(Default)]
+struct MachineQueue {
+    lock: Arc<tokio::sync::Mutex<()>>,
+    waiting: AtomicUsize,
+}
+
+/// Exclusive access to a machine, held until the ticket is dropped
+pub struct QueueTicket {
+    /// How many drops were ahead of this one when it joined the queue
+    pub position: usize,
+    _guard: OwnedMutexGuard<()>,
+    _waiting: Waiting,
+}
+
+/// The wait for a machine ran past the configured limit
+#[derive(Debug)]
+pub struct QueueTimeout {
+    pub position: usize,
+}
+
+/// Counts a request against its machine's queue for as long as it is alive
+struct Waiting(Arc<MachineQueue>);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl MachineQueues {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn acquire(
+        &self,
+        machine: i32,
+        timeout: Duration,
+    ) -> Result<QueueTicket, QueueTimeout> {
+        let queue = self
+            .queues
+            .lock()
+            .unwrap()
+            .entry(machine)
+            .or_default()
+            .clone();
+
+        let position = queue.waiting.fetch_add(1, Ordering::SeqCst);
+        let waiting = Waiting(queue.clone());
+
+        // tokio's Mutex hands out the lock in the order it was requested
+        match tokio::time::timeout(timeout, queue.lock.clone().lock_owned()).await {
+            Ok(guard) => Ok(QueueTicket {
+                position,
+                _guard: guard,
+                _waiting: waiting,
+            }),
+            Err(_) => Err(QueueTimeout { position }),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind before it misses some
+const EVENT_BUFFER: usize = 256;
+
+/// Something that changed on a machine, pushed to `/api/v2/events` listeners
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Event {
+    MachineOnline {
+        machine: String,
+    },
+    MachineOffline {
+        machine: String,
+        error: String,
+    },
+    /// The machine reported a slot as stocked again
+    SlotStocked {
+        machine: String,
+        slot: i32,
+    },
+    /// The machine reported a slot as empty
+    SlotEmpty {
+        machine: String,
+        slot: i32,
+    },
+    SlotCount {
+        machine: String,
+        slot: i32,
+        count: i32,
+    },
+    Drop {
+        machine: String,
+        slot: i32,
+        item: String,
+    },
+}
+
+impl Event {
+    /// The SSE event name, matching the `type` field
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::MachineOnline { .. } => "machineOnline",
+            Event::MachineOffline { .. } => "machineOffline",
+            Event::SlotStocked { .. } => "slotStocked",
+            Event::SlotEmpty { .. } => "slotEmpty",
+            Event::SlotCount { .. } => "slotCount",
+            Event::Drop { .. } => "drop",
+        }
+    }
+}
+
+/// Fans events out to every connected listener
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER);
+        EventBus { sender }
+    }
+}
+
+impl EventBus {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: Event) {
+        log::debug!("Publishing {} event", event.kind());
+        // An error only means nobody is listening right now
+        let _ = self.sender.send(event);
+    }
+
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
@@ -1,3 +1,73 @@
+use async_trait::async_trait;
+use ldap3::LdapError;
+use std::error::Error;
+use std::sync::Arc;
+
+use user::LdapUser;
+
+#[derive(Debug)]
+pub enum BalanceError {
+    /// The balance in LDAP no longer matches the value the update was based on
+    Conflict,
+    /// No connection to LDAP could be had from the pool
+    Unavailable(String),
+    LdapError(LdapError),
+}
+
+impl Error for BalanceError {}
+
+impl std::fmt::Display for BalanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            BalanceError::Conflict => write!(f, "drinkBalance was modified concurrently"),
+            BalanceError::Unavailable(e) => write!(f, "LDAP is unavailable: {e}"),
+            BalanceError::LdapError(le) => write!(f, "LDAP Error: {le}"),
+        }
+    }
+}
+
+impl From<LdapError> for BalanceError {
+    fn from(e: LdapError) -> Self {
+        BalanceError::LdapError(e)
+    }
+}
+
+/// Where users and their drink balances are kept
+///
+/// Drops go through this rather than `LdapClient` directly, so they can be run
+/// against something other than the real directory.
+#[async_trait]
+pub trait AccountBackend: Send + Sync {
+    async fn get_user(&self, uid: &str) -> Option<LdapUser>;
+
+    /// Atomically move a balance from `old` to `new`, see `LdapClient::update_balance`
+    async fn update_balance(
+        &self,
+        dn: &str,
+        old: Option<i64>,
+        new: i64,
+    ) -> Result<(), BalanceError>;
+}
+
+/// The accounts shared by the drop service
+pub type Accounts = Arc<dyn AccountBackend>;
+
+#[async_trait]
+impl AccountBackend for client::LdapClient {
+    async fn get_user(&self, uid: &str) -> Option<LdapUser> {
+        self.clone().get_user(uid).await
+    }
+
+    async fn update_balance(
+        &self,
+        dn: &str,
+        old: Option<i64>,
+        new: i64,
+    ) -> Result<(), BalanceError> {
+        self.clone().update_balance(dn, old, new).await
+    }
+}
+
 pub mod client;
 pub mod search;
 pub mod user;
@@ -12,6 +12,7 @@ use trust_dns_resolver::{
 
 use super::search::SearchAttrs;
 use super::user::{LdapUser, LdapUserChangeSet};
+use super::BalanceError;
 
 type Pool = managed::Pool<LdapManager>;
 
@@ -50,13 +51,12 @@ impl managed::Manager for LdapManager {
                 .choose(&mut rand::rngs::StdRng::from_entropy())
                 .unwrap(),
         )
-        .await
-        .unwrap();
+        .await?;
         drive!(conn);
 
         ldap.simple_bind(&self.bind_dn, &self.bind_pw)
-            .await
-            .unwrap();
+            .await?
+            .success()?;
 
         Ok(ldap)
     }
@@ -125,6 +125,20 @@ impl LdapClient {
             .collect()
     }
 
+    /// Check that LDAP is answering, by taking a connection from the pool
+    ///
+    /// Pooled connections are checked with a WhoAmI when they are recycled, and
+    /// new ones have just bound, so getting one at all means LDAP is up.
+    pub async fn ping(&self) -> Result<(), String> {
+        self.ldap.get().await.map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    /// How many connections the pool has open and idle
+    #[must_use]
+    pub fn pool_status(&self) -> deadpool::Status {
+        self.ldap.status()
+    }
+
     pub async fn get_user(&mut self, uid: &str) -> Option<LdapUser> {
         let mut ldap = self.ldap.get().await.unwrap();
 
@@ -142,7 +156,7 @@ impl LdapClient {
             .unwrap();
 
         if results.len() == 1 {
-            let user = SearchEntry::construct(results.get(0).unwrap().to_owned());
+            let user = SearchEntry::construct(results.first().unwrap().to_owned());
             Some(LdapUser::from_entry(&user))
         } else {
             None
@@ -166,7 +180,7 @@ impl LdapClient {
             .unwrap();
 
         if results.len() == 1 {
-            let user = SearchEntry::construct(results.get(0).unwrap().to_owned());
+            let user = SearchEntry::construct(results.first().unwrap().to_owned());
             Some(LdapUser::from_entry(&user))
         } else {
             None
@@ -189,26 +203,64 @@ impl LdapClient {
             .unwrap();
 
         if results.len() == 1 {
-            let user = SearchEntry::construct(results.get(0).unwrap().to_owned());
+            let user = SearchEntry::construct(results.first().unwrap().to_owned());
             Some(LdapUser::from_entry(&user))
         } else {
             None
         }
     }
 
-    pub async fn update_user(&mut self, change_set: &LdapUserChangeSet) {
+    pub async fn update_user(&mut self, change_set: &LdapUserChangeSet) -> Result<(), LdapError> {
         let mut ldap = self.ldap.get().await.unwrap();
 
         let mut changes = Vec::new();
-        if change_set.drinkBalance.is_some() {
+        if let Some(drink_balance) = change_set.drinkBalance {
             changes.push(Mod::Replace(
                 String::from("drinkBalance"),
-                HashSet::from([change_set.drinkBalance.unwrap().to_string()]),
+                HashSet::from([drink_balance.to_string()]),
+            ));
+        }
+        ldap.modify(&change_set.dn, changes).await?.success()?;
+        Ok(())
+    }
+
+    /// Atomically move `drinkBalance` from `old` to `new`
+    ///
+    /// The old value is deleted and the new one added in a single modify, so the
+    /// server rejects the change if the balance moved since it was read.
+    pub async fn update_balance(
+        &mut self,
+        dn: &str,
+        old: Option<i64>,
+        new: i64,
+    ) -> Result<(), BalanceError> {
+        let mut ldap = self
+            .ldap
+            .get()
+            .await
+            .map_err(|e| BalanceError::Unavailable(e.to_string()))?;
+
+        let mut changes = Vec::new();
+        if let Some(old) = old {
+            changes.push(Mod::Delete(
+                String::from("drinkBalance"),
+                HashSet::from([old.to_string()]),
             ));
         }
-        match ldap.modify(&change_set.dn, changes).await {
-            Ok(_) => {}
-            Err(e) => eprintln!("{:#?}", e),
+        changes.push(Mod::Add(
+            String::from("drinkBalance"),
+            HashSet::from([new.to_string()]),
+        ));
+
+        let result = ldap.modify(dn, changes).await?;
+        match result.rc {
+            // noSuchAttribute / constraintViolation / attributeOrValueExists: someone
+            // else got there first, like setting a balance the user didn't have yet
+            16 | 19 | 20 => Err(BalanceError::Conflict),
+            _ => {
+                result.success()?;
+                Ok(())
+            }
         }
     }
 }
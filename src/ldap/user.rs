@@ -43,11 +43,8 @@ where
     T: FromStr,
     <T as FromStr>::Err: Debug,
 {
-    match entry.get(field).map(|f| f.get(0).unwrap().parse::<T>()) {
-        Some(result) => match result {
-            Ok(r) => Some(r),
-            Err(_) => None,
-        },
+    match entry.get(field).map(|f| f.first().unwrap().parse::<T>()) {
+        Some(result) => result.ok(),
         None => None,
     }
 }
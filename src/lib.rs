@@ -1,10 +1,13 @@
 use serde::Serialize;
 
 pub mod db;
+pub mod drop;
+pub mod events;
 pub mod ldap;
 pub mod machine;
 pub mod oidc;
 pub mod routes;
+pub mod telemetry;
 
 #[derive(Debug, Serialize)]
 struct DrinkResponse {
@@ -16,6 +19,8 @@ struct Machine {
     pub display_name: String,
     pub id: i32,
     pub is_online: bool,
+    /// Current or upcoming, so clients can warn ahead of time
+    pub maintenance: Option<db::models::Maintenance>,
     pub name: String,
     pub slots: Box<[Slot]>,
 }
@@ -1,9 +1,10 @@
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::env;
-use std::time::Duration;
+use crate::db::models::Machine;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::Arc;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MachineResponse {
     #[serde(skip)]
     pub name: String,
@@ -11,38 +12,53 @@ pub struct MachineResponse {
     pub temp: f32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SlotResponse {
     pub number: i32,
     pub stocked: bool,
 }
 
-pub async fn get_status(name: &str) -> Result<MachineResponse, reqwest::Error> {
-    let client = reqwest::Client::new();
+#[derive(Debug)]
+pub enum MachineError {
+    /// The machine could not be reached at all, so nothing was sent
+    Connect(String),
+    /// The request was sent but the machine didn't answer in time
+    Timeout,
+    /// The machine answered with an error
+    Status {
+        status: u16,
+        message: String,
+    },
+    Other(String),
+}
 
-    let mut res = client
-        .get(format!("https://{}.csh.rit.edu/slots", name))
-        .header("X-Auth-Token", env::var("MACHINE_SECRET").unwrap())
-        .timeout(Duration::from_secs(5))
-        .send()
-        .await?
-        .json::<MachineResponse>()
-        .await?;
+impl Error for MachineError {}
 
-    res.name = name.to_string();
-    Ok(res)
+impl std::fmt::Display for MachineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MachineError::Connect(e) => write!(f, "Could not connect: {e}"),
+            MachineError::Timeout => write!(f, "Timed out"),
+            MachineError::Status { status, message } => write!(f, "{status}: {message}"),
+            MachineError::Other(e) => write!(f, "{e}"),
+        }
+    }
 }
 
-pub async fn drop(name: &str, slot: i32) -> Result<reqwest::Response, reqwest::Error> {
-    let client = reqwest::Client::new();
-    // We pass the right slot, but bubbler does a -1... TODO: not be out of sync
-    let body = HashMap::from([("slot", slot+1)]);
-
-    client
-        .post(format!("https://{}.csh.rit.edu/drop", name))
-        .header("X-Auth-Token", env::var("MACHINE_SECRET").unwrap())
-        .timeout(Duration::from_secs(15))
-        .json(&body)
-        .send()
-        .await
+/// Something that can report on and dispense from drink machines
+#[async_trait]
+pub trait MachineBackend: Send + Sync {
+    async fn get_status(&self, machine: &Machine) -> Result<MachineResponse, MachineError>;
+
+    async fn drop_slot(&self, machine: &Machine, slot: i32) -> Result<(), MachineError>;
 }
+
+/// The backend shared between handlers as an extension
+pub type Machines = Arc<dyn MachineBackend>;
+
+pub mod bubbler;
+pub mod reconcile;
+pub mod signing;
+pub mod simulator;
+pub mod status;
+pub mod temperature;
@@ -0,0 +1,138 @@
+use super::signing::{self, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use super::{MachineBackend, MachineError, MachineResponse};
+use crate::db::models::Machine;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+/// Where machines live when they don't have a URL of their own
+pub const DEFAULT_URL_TEMPLATE: &str = "https://{name}.csh.rit.edu";
+
+/// Talks to the bubbler firmware running on the real machines
+#[derive(Clone)]
+pub struct BubblerBackend {
+    http_client: reqwest::Client,
+    secret: String,
+    url_template: String,
+    /// Also send the secret itself, for machines that don't check signatures
+    legacy: bool,
+}
+
+impl BubblerBackend {
+    #[must_use]
+    pub fn new(secret: &str, url_template: &str, legacy: bool) -> Self {
+        BubblerBackend {
+            http_client: reqwest::Client::new(),
+            secret: secret.to_owned(),
+            url_template: url_template.to_owned(),
+            legacy,
+        }
+    }
+
+    /// The machine's own base URL, or the template filled in with its name
+    fn base_url(&self, machine: &Machine) -> String {
+        let url = match &machine.url {
+            Some(url) => url.clone(),
+            None => self.url_template.replace("{name}", &machine.name),
+        };
+        url.trim_end_matches('/').to_owned()
+    }
+
+    /// Build a request to the machine, signed with every secret it may hold
+    fn request(
+        &self,
+        machine: &Machine,
+        method: reqwest::Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.base_url(machine), path);
+        // The machine sees the whole path, including any prefix in its base URL
+        let signed_path = reqwest::Url::parse(&url)
+            .map(|url| url.path().to_owned())
+            .unwrap_or_else(|_| path.to_owned());
+        let secrets = signing::machine_secrets(machine, &self.secret);
+        let signature = signing::sign(&secrets, method.as_str(), &signed_path, &body);
+        let mut request = self
+            .http_client
+            .request(method, url)
+            .header(TIMESTAMP_HEADER, signature.timestamp)
+            .header(NONCE_HEADER, signature.nonce)
+            .header(SIGNATURE_HEADER, signature.signature);
+        if self.legacy {
+            request = request.header("X-Auth-Token", secrets[0]);
+        }
+        if !body.is_empty() {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body);
+        }
+        request
+    }
+}
+
+impl Default for BubblerBackend {
+    fn default() -> Self {
+        Self::new(
+            &env::var("MACHINE_SECRET").unwrap(),
+            &env::var("MACHINE_URL_TEMPLATE").unwrap_or_else(|_| DEFAULT_URL_TEMPLATE.to_owned()),
+            signing::legacy_auth_enabled(),
+        )
+    }
+}
+
+impl From<reqwest::Error> for MachineError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_connect() {
+            MachineError::Connect(e.to_string())
+        } else if e.is_timeout() {
+            MachineError::Timeout
+        } else {
+            MachineError::Other(e.to_string())
+        }
+    }
+}
+
+#[async_trait]
+impl MachineBackend for BubblerBackend {
+    async fn get_status(&self, machine: &Machine) -> Result<MachineResponse, MachineError> {
+        let mut res = self
+            .request(machine, reqwest::Method::GET, "/slots", Vec::new())
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .json::<MachineResponse>()
+            .await?;
+
+        res.name = machine.name.clone();
+        // Bubbler reports slots with the same numbers as the database, only /drop
+        // is shifted by the slot offset
+        Ok(res)
+    }
+
+    async fn drop_slot(&self, machine: &Machine, slot: i32) -> Result<(), MachineError> {
+        let body = HashMap::from([("slot", slot + machine.slot_offset)]);
+        // Serialize ourselves, since the signature has to cover the exact bytes
+        let body = serde_json::to_vec(&body).unwrap();
+
+        let response = self
+            .request(machine, reqwest::Method::POST, "/drop", body)
+            .timeout(Duration::from_secs(15))
+            .send()
+            .await?;
+
+        if let Err(e) = response.error_for_status_ref() {
+            let status = e.status().map(|s| s.as_u16()).unwrap_or(500);
+            let message = response
+                .json::<serde_json::Value>()
+                .await
+                .ok()
+                .and_then(|content| content["error"].as_str().map(str::to_owned))
+                .unwrap_or_default();
+            return Err(MachineError::Status { status, message });
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use super::status::StatusCache;
+use super::MachineResponse;
+use crate::db;
+use crate::db::models::Machine;
+use chrono::{DateTime, Utc};
+use metrics::gauge;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often slot layouts are compared with the machines, in seconds
+pub const DEFAULT_RECONCILE_INTERVAL_SECS: u64 = 3600;
+
+/// How the configured slots line up with the ones the machine reports
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayoutCheck {
+    /// Configured, but not reported by the machine
+    pub missing: Vec<i32>,
+    /// Reported by the machine, but not configured
+    pub unconfigured: Vec<i32>,
+    /// How far the machine's numbers are from the configured ones, when every
+    /// slot is off by the same amount
+    pub shift: Option<i32>,
+}
+
+impl LayoutCheck {
+    /// Compare slot numbers from the database with what the machine reported
+    ///
+    /// Machines report slots with the same numbers as the database, since the
+    /// slot offset only applies to dropping.
+    #[must_use]
+    pub fn new(numbers: &[i32], state: &MachineResponse) -> Self {
+        let configured: HashSet<i32> = numbers.iter().copied().collect();
+        let reported: HashSet<i32> = state.slots.iter().map(|slot| slot.number).collect();
+
+        let mut missing: Vec<i32> = configured.difference(&reported).copied().collect();
+        let mut unconfigured: Vec<i32> = reported.difference(&configured).copied().collect();
+        missing.sort_unstable();
+        unconfigured.sort_unstable();
+
+        let mut configured: Vec<i32> = configured.into_iter().collect();
+        let mut reported: Vec<i32> = reported.into_iter().collect();
+        configured.sort_unstable();
+        reported.sort_unstable();
+        let shift = match (configured.first(), reported.first()) {
+            (Some(first), Some(reported_first))
+                if configured.len() == reported.len() && first != reported_first =>
+            {
+                let shift = reported_first - first;
+                configured
+                    .iter()
+                    .zip(&reported)
+                    .all(|(configured, reported)| reported - configured == shift)
+                    .then_some(shift)
+            }
+            _ => None,
+        };
+
+        LayoutCheck {
+            missing,
+            unconfigured,
+            shift,
+        }
+    }
+
+    #[must_use]
+    pub fn matches(&self) -> bool {
+        self.missing.is_empty() && self.unconfigured.is_empty()
+    }
+}
+
+/// The outcome of reconciling one machine
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reconciliation {
+    pub machine: String,
+    pub slot_offset: i32,
+    pub checked_at: DateTime<Utc>,
+    /// Missing when the machine couldn't be checked
+    pub layout: Option<LayoutCheck>,
+    pub error: Option<String>,
+}
+
+/// Periodically compares every active machine's slots with what it reports
+#[derive(Clone)]
+pub struct Reconciler {
+    pool: Arc<Pool<Postgres>>,
+    statuses: StatusCache,
+    interval: Duration,
+    results: Arc<RwLock<HashMap<String, Reconciliation>>>,
+}
+
+impl Reconciler {
+    pub fn new(pool: Arc<Pool<Postgres>>, statuses: StatusCache) -> Self {
+        let interval = env::var("RECONCILE_INTERVAL")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_RECONCILE_INTERVAL_SECS);
+        Reconciler {
+            pool,
+            statuses,
+            interval: Duration::from_secs(interval),
+            results: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Start reconciling on the configured interval
+    pub fn spawn(&self) -> tokio::task::JoinHandle<()> {
+        let reconciler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reconciler.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if let Err(e) = reconciler.run().await {
+                    log::error!("Error reconciling slots: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Check every active machine now, replacing the previous results
+    pub async fn run(&self) -> Result<Vec<Reconciliation>, sqlx::Error> {
+        let machines = db::machines::get_active_machines(&self.pool).await?;
+
+        let mut results = Vec::with_capacity(machines.len());
+        for machine in &machines {
+            // One machine failing shouldn't hide the rest, so its error is recorded
+            // in its own result instead
+            results.push(self.reconcile(machine).await);
+        }
+
+        let mut stored = self.results.write().unwrap();
+        stored.clear();
+        for result in &results {
+            stored.insert(result.machine.clone(), result.clone());
+        }
+        Ok(results)
+    }
+
+    async fn reconcile(&self, machine: &Machine) -> Reconciliation {
+        let (layout, error) = match self.check(machine).await {
+            Ok(check) => (Some(check), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        Reconciliation {
+            machine: machine.name.clone(),
+            slot_offset: machine.slot_offset,
+            checked_at: Utc::now(),
+            layout,
+            error,
+        }
+    }
+
+    async fn check(&self, machine: &Machine) -> Result<LayoutCheck, String> {
+        let slots = db::slots::get_machine_slots(&self.pool, machine.id)
+            .await
+            .map_err(|e| {
+                log::error!("Error getting slots for machine {}: {}", machine.name, e);
+                String::from("could not get slots")
+            })?;
+        let numbers: Vec<i32> = slots.iter().map(|slot| slot.number).collect();
+
+        let state = self
+            .statuses
+            .get_status(machine, false)
+            .await
+            .map_err(|e| e.to_string())?;
+        let check = LayoutCheck::new(&numbers, &state);
+        if !check.matches() {
+            log::warn!(
+                "Slots for machine {} don't match the machine: missing {:?}, unconfigured {:?}, shifted by {:?}",
+                machine.name,
+                check.missing,
+                check.unconfigured,
+                check.shift
+            );
+        }
+        gauge!(
+            "bartender_slot_mismatches",
+            (check.missing.len() + check.unconfigured.len()) as f64,
+            "machine" => machine.name.clone()
+        );
+        Ok(check)
+    }
+
+    /// The results of the last run, by machine name
+    #[must_use]
+    pub fn results(&self) -> Vec<Reconciliation> {
+        let mut results: Vec<_> = self.results.read().unwrap().values().cloned().collect();
+        results.sort_by(|a, b| a.machine.cmp(&b.machine));
+        results
+    }
+}
@@ -0,0 +1,366 @@
+//! HMAC request signing between bartender and the machines
+//!
+//! A signed request carries `X-Timestamp` (unix seconds), `X-Nonce` and
+//! `X-Signature`, the hex HMAC-SHA256 of
+//! `"{method}\n{path}\n{timestamp}\n{nonce}\n{body}"`, where the path includes
+//! the query string. While a secret is being rotated `X-Signature` holds one
+//! comma separated signature per valid secret, and the receiver accepts the
+//! request if any of them match. Requests from a machine also name it in
+//! `X-Machine` so we know which secrets to check.
+//!
+//! Replay protection is the same both ways: a request is rejected if its
+//! timestamp is too far from the receiver's clock, or if its nonce was already
+//! seen within that window.
+
+use crate::db::models::Machine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::sync::Mutex;
+
+pub const MACHINE_HEADER: &str = "X-Machine";
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+pub const NONCE_HEADER: &str = "X-Nonce";
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// How far a signed request's timestamp may drift from our clock, in seconds
+pub const DEFAULT_MAX_AGE_SECS: i64 = 300;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// A signing header is missing or malformed
+    Missing(&'static str),
+    UnknownMachine(String),
+    /// The timestamp is outside the allowed window
+    Stale,
+    /// The nonce has already been used
+    Replayed,
+    Invalid,
+}
+
+impl Error for SignatureError {}
+
+impl std::fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SignatureError::Missing(header) => write!(f, "missing or malformed {header} header"),
+            SignatureError::UnknownMachine(name) => write!(f, "unknown machine {name}"),
+            SignatureError::Stale => write!(f, "request timestamp is too old or in the future"),
+            SignatureError::Replayed => write!(f, "request has already been seen"),
+            SignatureError::Invalid => write!(f, "signature does not match"),
+        }
+    }
+}
+
+/// The headers to attach to a signed request
+pub struct Signature {
+    pub timestamp: i64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+fn mac(
+    secret: &str,
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(format!("{method}\n{path}\n{timestamp}\n{nonce}\n").as_bytes());
+    mac.update(body);
+    mac
+}
+
+/// Sign a request with every secret the receiver might currently hold
+#[must_use]
+pub fn sign(secrets: &[&str], method: &str, path: &str, body: &[u8]) -> Signature {
+    let timestamp = Utc::now().timestamp();
+    let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let signature = secrets
+        .iter()
+        .map(|secret| {
+            hex::encode(
+                mac(secret, method, path, timestamp, &nonce, body)
+                    .finalize()
+                    .into_bytes(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    Signature {
+        timestamp,
+        nonce,
+        signature,
+    }
+}
+
+/// Check that any of the signatures was made with any of the secrets
+#[must_use]
+pub fn verify(
+    secrets: &[&str],
+    method: &str,
+    path: &str,
+    timestamp: i64,
+    nonce: &str,
+    body: &[u8],
+    signatures: &str,
+) -> bool {
+    signatures
+        .split(',')
+        .filter_map(|signature| hex::decode(signature.trim()).ok())
+        .any(|signature| {
+            secrets.iter().any(|secret| {
+                mac(secret, method, path, timestamp, nonce, body)
+                    .verify_slice(&signature)
+                    .is_ok()
+            })
+        })
+}
+
+/// Generate a fresh machine secret
+#[must_use]
+pub fn generate_secret() -> String {
+    hex::encode(rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// The secrets a machine may be using right now, newest first
+///
+/// A rotated-out secret stays valid until `previous_secret_expires`. When a
+/// machine moves off the shared secret there is no previous secret of its own,
+/// so the shared secret is what stays valid in the meantime.
+#[must_use]
+pub fn machine_secrets<'a>(machine: &'a Machine, shared: &'a str) -> Vec<&'a str> {
+    let mut secrets = vec![machine.secret.as_deref().unwrap_or(shared)];
+    if machine
+        .previous_secret_expires
+        .is_some_and(|expires| expires > Utc::now())
+    {
+        secrets.push(machine.previous_secret.as_deref().unwrap_or(shared));
+    }
+    secrets
+}
+
+/// Remembers recent nonces so a captured request can't be sent again
+pub struct ReplayGuard {
+    max_age: i64,
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl ReplayGuard {
+    #[must_use]
+    pub fn new(max_age: i64) -> Self {
+        ReplayGuard {
+            max_age,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Accept a timestamp and nonce once, if the timestamp is recent
+    pub fn check(&self, scope: &str, timestamp: i64, nonce: &str) -> Result<(), SignatureError> {
+        let now = Utc::now().timestamp();
+        if (now - timestamp).abs() > self.max_age {
+            return Err(SignatureError::Stale);
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        // Anything older than the window would be rejected as stale anyway
+        seen.retain(|_, seen_at| now - *seen_at <= self.max_age);
+        match seen.insert(format!("{scope}:{nonce}"), timestamp) {
+            Some(_) => Err(SignatureError::Replayed),
+            None => Ok(()),
+        }
+    }
+}
+
+/// How machines prove who they are when they call us
+pub struct MachineAuth {
+    /// MACHINE_SECRET, for machines without a secret of their own
+    pub shared_secret: String,
+    /// Whether the plain `X-Auth-Token` header is still accepted
+    pub legacy: bool,
+    pub replay: ReplayGuard,
+}
+
+impl MachineAuth {
+    #[must_use]
+    pub fn from_env() -> Self {
+        MachineAuth {
+            shared_secret: env::var("MACHINE_SECRET").unwrap(),
+            legacy: legacy_auth_enabled(),
+            replay: ReplayGuard::new(
+                env::var("SIGNATURE_MAX_AGE")
+                    .ok()
+                    .and_then(|age| age.parse().ok())
+                    .unwrap_or(DEFAULT_MAX_AGE_SECS),
+            ),
+        }
+    }
+}
+
+/// Whether MACHINE_LEGACY_AUTH allows the plain shared secret header
+///
+/// This defaults to on so machines keep working until bubbler signs requests.
+#[must_use]
+pub fn legacy_auth_enabled() -> bool {
+    env::var("MACHINE_LEGACY_AUTH")
+        .map(|value| value != "false" && value != "0")
+        .unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::thread;
+
+    const BODY: &[u8] = br#"{"slot":1}"#;
+
+    fn verify_signed(secrets: &[&str], signature: &Signature, path: &str, body: &[u8]) -> bool {
+        verify(
+            secrets,
+            "POST",
+            path,
+            signature.timestamp,
+            &signature.nonce,
+            body,
+            &signature.signature,
+        )
+    }
+
+    fn machine(secret: Option<&str>, previous: Option<&str>, expires_in: i64) -> Machine {
+        Machine {
+            id: 1,
+            name: String::from("test"),
+            display_name: String::from("Test"),
+            active: true,
+            url: None,
+            secret: secret.map(str::to_owned),
+            previous_secret: previous.map(str::to_owned),
+            previous_secret_expires: Some(Utc::now() + Duration::seconds(expires_in)),
+            max_temp: None,
+            slot_offset: 1,
+            maintenance_message: None,
+            maintenance_start: None,
+            maintenance_end: None,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let signature = sign(&["secret"], "POST", "/drop?x=1", BODY);
+        assert!(verify_signed(&["secret"], &signature, "/drop?x=1", BODY));
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected() {
+        let signature = sign(&["secret"], "POST", "/drop", BODY);
+        assert!(!verify_signed(&["other"], &signature, "/drop", BODY));
+    }
+
+    #[test]
+    fn tampered_request_is_rejected() {
+        let signature = sign(&["secret"], "POST", "/drop", BODY);
+        assert!(!verify_signed(
+            &["secret"],
+            &signature,
+            "/drop",
+            br#"{"slot":2}"#
+        ));
+        assert!(!verify_signed(
+            &["secret"],
+            &signature,
+            "/drop?slot=2",
+            BODY
+        ));
+        assert!(!verify(
+            &["secret"],
+            "PUT",
+            "/drop",
+            signature.timestamp,
+            &signature.nonce,
+            BODY,
+            &signature.signature,
+        ));
+        assert!(!verify(
+            &["secret"],
+            "POST",
+            "/drop",
+            signature.timestamp + 1,
+            &signature.nonce,
+            BODY,
+            &signature.signature,
+        ));
+    }
+
+    #[test]
+    fn either_secret_is_accepted_during_rotation() {
+        // Signed with both, for a receiver that may or may not have the new one yet
+        let signature = sign(&["new", "old"], "POST", "/drop", BODY);
+        assert_eq!(signature.signature.split(',').count(), 2);
+        assert!(verify_signed(&["old"], &signature, "/drop", BODY));
+        assert!(verify_signed(&["new"], &signature, "/drop", BODY));
+
+        // A sender still on the old secret, checked against both
+        let signature = sign(&["old"], "POST", "/drop", BODY);
+        assert!(verify_signed(&["new", "old"], &signature, "/drop", BODY));
+        assert!(!verify_signed(&["new"], &signature, "/drop", BODY));
+    }
+
+    #[test]
+    fn previous_secret_only_counts_until_it_expires() {
+        let rotating = machine(Some("new"), Some("old"), 60);
+        assert_eq!(machine_secrets(&rotating, "shared"), vec!["new", "old"]);
+
+        let rotated = machine(Some("new"), Some("old"), -60);
+        assert_eq!(machine_secrets(&rotated, "shared"), vec!["new"]);
+
+        // Moving off the shared secret keeps the shared one valid meanwhile
+        let first = machine(Some("new"), None, 60);
+        assert_eq!(machine_secrets(&first, "shared"), vec!["new", "shared"]);
+    }
+
+    #[test]
+    fn stale_and_future_timestamps_are_rejected() {
+        let guard = ReplayGuard::new(DEFAULT_MAX_AGE_SECS);
+        let now = Utc::now().timestamp();
+        assert_eq!(
+            guard.check("test", now - DEFAULT_MAX_AGE_SECS - 10, "a"),
+            Err(SignatureError::Stale)
+        );
+        assert_eq!(
+            guard.check("test", now + DEFAULT_MAX_AGE_SECS + 10, "b"),
+            Err(SignatureError::Stale)
+        );
+        assert_eq!(guard.check("test", now, "c"), Ok(()));
+    }
+
+    #[test]
+    fn replayed_nonce_is_rejected() {
+        let guard = ReplayGuard::new(DEFAULT_MAX_AGE_SECS);
+        let now = Utc::now().timestamp();
+        assert_eq!(guard.check("test", now, "nonce"), Ok(()));
+        assert_eq!(
+            guard.check("test", now, "nonce"),
+            Err(SignatureError::Replayed)
+        );
+        // Nonces are only unique per machine
+        assert_eq!(guard.check("other", now, "nonce"), Ok(()));
+    }
+
+    #[test]
+    fn nonces_are_forgotten_after_the_window() {
+        let guard = ReplayGuard::new(1);
+        assert_eq!(guard.check("test", Utc::now().timestamp(), "nonce"), Ok(()));
+        thread::sleep(std::time::Duration::from_millis(2100));
+        assert_eq!(guard.check("test", Utc::now().timestamp(), "nonce"), Ok(()));
+    }
+}
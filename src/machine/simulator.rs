@@ -0,0 +1,264 @@
+use super::{MachineBackend, MachineError, MachineResponse, SlotResponse};
+use crate::db::models::Machine;
+use async_trait::async_trait;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How far ahead of its reported slot numbers bubbler's /drop numbers are, which
+/// is what the default slot offset makes up for
+const BUBBLER_DROP_SHIFT: i32 = 1;
+
+/// An in-process stand-in for bubbler, for tests and local development
+///
+/// Every machine name is accepted and gets its own stock the first time it is
+/// seen, so no configuration is needed beyond the machines in the database.
+pub struct SimulatorBackend {
+    slots: i32,
+    stock: u32,
+    temp: f32,
+    latency: Duration,
+    failure_rate: f64,
+    timeout_rate: f64,
+    offline: HashSet<String>,
+    machines: Mutex<HashMap<String, Vec<u32>>>,
+}
+
+impl Default for SimulatorBackend {
+    fn default() -> Self {
+        SimulatorBackend {
+            slots: 6,
+            stock: 10,
+            temp: 38.0,
+            latency: Duration::ZERO,
+            failure_rate: 0.0,
+            timeout_rate: 0.0,
+            offline: HashSet::new(),
+            machines: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl SimulatorBackend {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configure the simulator from SIMULATOR_* environment variables
+    ///
+    /// # Panics
+    ///
+    /// When a variable is set to something the simulator can't use, rather than
+    /// quietly running with the default instead.
+    #[must_use]
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            let value = env::var(name).ok()?;
+            match value.parse() {
+                Ok(parsed) => Some(parsed),
+                Err(_) => panic!("{name} is invalid: {value}"),
+            }
+        }
+
+        let defaults = Self::default();
+        let mut simulator = Self::new()
+            .slots(var("SIMULATOR_SLOTS").unwrap_or(defaults.slots))
+            .stock(var("SIMULATOR_STOCK").unwrap_or(defaults.stock))
+            .temp(var("SIMULATOR_TEMP").unwrap_or(defaults.temp))
+            .latency(Duration::from_millis(
+                var("SIMULATOR_LATENCY_MS").unwrap_or(0),
+            ))
+            .failure_rate(var("SIMULATOR_FAILURE_RATE").unwrap_or(defaults.failure_rate))
+            .timeout_rate(var("SIMULATOR_TIMEOUT_RATE").unwrap_or(defaults.timeout_rate));
+        if let Ok(offline) = env::var("SIMULATOR_OFFLINE") {
+            for name in offline.split(',').filter(|name| !name.is_empty()) {
+                simulator = simulator.offline(name);
+            }
+        }
+        simulator
+    }
+
+    /// # Panics
+    ///
+    /// When `slots` isn't positive.
+    #[must_use]
+    pub fn slots(mut self, slots: i32) -> Self {
+        assert!(
+            slots > 0,
+            "the simulator needs at least one slot, got {slots}"
+        );
+        self.slots = slots;
+        self
+    }
+
+    #[must_use]
+    pub fn stock(mut self, stock: u32) -> Self {
+        self.stock = stock;
+        self
+    }
+
+    #[must_use]
+    pub fn temp(mut self, temp: f32) -> Self {
+        self.temp = temp;
+        self
+    }
+
+    #[must_use]
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// The chance (0.0 to 1.0) that a drop fails with a machine error
+    ///
+    /// # Panics
+    ///
+    /// When the chance isn't between 0.0 and 1.0.
+    #[must_use]
+    pub fn failure_rate(mut self, failure_rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&failure_rate),
+            "failure rate must be between 0.0 and 1.0, got {failure_rate}"
+        );
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    /// The chance (0.0 to 1.0) that a drop dispenses but never answers
+    ///
+    /// # Panics
+    ///
+    /// When the chance isn't between 0.0 and 1.0.
+    #[must_use]
+    pub fn timeout_rate(mut self, timeout_rate: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&timeout_rate),
+            "timeout rate must be between 0.0 and 1.0, got {timeout_rate}"
+        );
+        self.timeout_rate = timeout_rate;
+        self
+    }
+
+    /// Make a machine unreachable
+    #[must_use]
+    pub fn offline(mut self, name: &str) -> Self {
+        self.offline.insert(name.to_owned());
+        self
+    }
+
+    /// Set the remaining stock of a single slot
+    pub fn set_stock(&self, name: &str, slot: i32, stock: u32) {
+        self.with_slots(name, |slots| {
+            if let Some(remaining) = slot_mut(slots, slot) {
+                *remaining = stock;
+            }
+        });
+    }
+
+    /// Run `f` against a machine's stock, creating it on first use
+    fn with_slots<T>(&self, name: &str, f: impl FnOnce(&mut Vec<u32>) -> T) -> T {
+        let mut machines = self.machines.lock().unwrap();
+        let slots = machines
+            .entry(name.to_owned())
+            .or_insert_with(|| vec![self.stock; self.slots as usize]);
+        f(slots)
+    }
+
+    async fn connect(&self, name: &str) -> Result<(), MachineError> {
+        tokio::time::sleep(self.latency).await;
+        if self.offline.contains(name) {
+            return Err(MachineError::Connect(format!("{name} is offline")));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MachineBackend for SimulatorBackend {
+    async fn get_status(&self, machine: &Machine) -> Result<MachineResponse, MachineError> {
+        let name = &machine.name;
+        self.connect(name).await?;
+
+        let slots = self.with_slots(name, |slots| {
+            slots
+                .iter()
+                .enumerate()
+                .map(|(index, stock)| SlotResponse {
+                    number: index as i32 + 1,
+                    stocked: *stock > 0,
+                })
+                .collect()
+        });
+        Ok(MachineResponse {
+            name: name.to_owned(),
+            slots,
+            temp: self.temp,
+        })
+    }
+
+    async fn drop_slot(&self, machine: &Machine, slot: i32) -> Result<(), MachineError> {
+        let name = &machine.name;
+        self.connect(name).await?;
+
+        if rand::thread_rng().gen_bool(self.failure_rate) {
+            return Err(MachineError::Status {
+                status: 500,
+                message: String::from("Simulated drop failure"),
+            });
+        }
+
+        // Like bubbler, take the shifted number in /drop but report status unshifted
+        let slot = slot + machine.slot_offset - BUBBLER_DROP_SHIFT;
+        let dropped = self.with_slots(name, |slots| match slot_mut(slots, slot) {
+            Some(remaining) if *remaining == 0 => Err(MachineError::Status {
+                status: 400,
+                message: format!("Slot {slot} is empty"),
+            }),
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(())
+            }
+            None => Err(MachineError::Status {
+                status: 400,
+                message: format!("Invalid slot {slot}"),
+            }),
+        });
+
+        if dropped.is_ok() && rand::thread_rng().gen_bool(self.timeout_rate) {
+            return Err(MachineError::Timeout);
+        }
+        dropped
+    }
+}
+
+/// Slots are numbered from 1, like bubbler reports them
+fn slot_mut(slots: &mut [u32], slot: i32) -> Option<&mut u32> {
+    slot.checked_sub(1)
+        .and_then(|index| slots.get_mut(index as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "failure rate")]
+    fn rejects_nan_failure_rate() {
+        let _ = SimulatorBackend::new().failure_rate(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "timeout rate")]
+    fn rejects_out_of_range_timeout_rate() {
+        let _ = SimulatorBackend::new().timeout_rate(1.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one slot")]
+    fn rejects_negative_slots() {
+        let _ = SimulatorBackend::new().slots(-1);
+    }
+}
@@ -0,0 +1,218 @@
+use super::temperature::TemperatureMonitor;
+use super::{MachineError, MachineResponse, Machines};
+use crate::db;
+use crate::db::models::Machine;
+use crate::events::{Event, EventBus};
+use chrono::{DateTime, Utc};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How often every active machine is polled, in seconds
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 15;
+
+/// What the poller last heard from a machine
+#[derive(Debug, Clone, Serialize)]
+pub struct CachedStatus {
+    /// The last successful response, kept while the machine is offline
+    pub state: Option<MachineResponse>,
+    /// When the machine last answered
+    pub last_seen: Option<DateTime<Utc>>,
+    pub checked_at: DateTime<Utc>,
+    /// Why the last poll failed, if it did
+    pub error: Option<String>,
+}
+
+impl CachedStatus {
+    pub fn is_online(&self) -> bool {
+        self.error.is_none() && self.state.is_some()
+    }
+}
+
+/// The latest status of every machine, kept up to date in the background
+///
+/// Handlers read from here instead of asking every machine on every request,
+/// so one offline machine doesn't slow down everything else.
+#[derive(Clone)]
+pub struct StatusCache {
+    pool: Arc<Pool<Postgres>>,
+    backend: Machines,
+    events: EventBus,
+    temperatures: TemperatureMonitor,
+    interval: Duration,
+    statuses: Arc<RwLock<HashMap<String, CachedStatus>>>,
+}
+
+impl StatusCache {
+    pub fn new(pool: Arc<Pool<Postgres>>, backend: Machines, events: EventBus) -> Self {
+        let interval = env::var("STATUS_POLL_INTERVAL")
+            .ok()
+            .and_then(|interval| interval.parse().ok())
+            .unwrap_or(DEFAULT_POLL_INTERVAL_SECS);
+        StatusCache {
+            temperatures: TemperatureMonitor::new(pool.clone()),
+            pool,
+            backend,
+            events,
+            interval: Duration::from_secs(interval),
+            statuses: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Start polling every active machine on the configured interval
+    pub fn spawn(&self) -> tokio::task::JoinHandle<()> {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(cache.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                cache.poll_all().await;
+            }
+        })
+    }
+
+    async fn poll_all(&self) {
+        let machines = match db::machines::get_active_machines(&self.pool).await {
+            Ok(machines) => machines,
+            Err(e) => {
+                log::error!("Error getting machines to poll: {}", e);
+                return;
+            }
+        };
+
+        // Only scheduled polls are sampled, so the history has a steady rate
+        let mut polls: FuturesUnordered<_> = machines
+            .iter()
+            .map(|machine| async move {
+                // The door is probably open while it's being serviced
+                if let Ok(state) = self.poll(machine).await {
+                    if !machine.in_maintenance() {
+                        self.temperatures.record(machine, state.temp).await;
+                    }
+                }
+            })
+            .collect();
+        while polls.next().await.is_some() {}
+
+        // Forget machines that were removed or deactivated
+        self.statuses
+            .write()
+            .unwrap()
+            .retain(|name, _| machines.iter().any(|machine| &machine.name == name));
+    }
+
+    /// Ask the machine directly and remember the answer
+    async fn poll(&self, machine: &Machine) -> Result<MachineResponse, MachineError> {
+        let result = self.backend.get_status(machine).await;
+        let now = Utc::now();
+
+        let mut statuses = self.statuses.write().unwrap();
+        let previous = statuses.remove(&machine.name);
+        let status = match &result {
+            Ok(state) => {
+                if let Some(previous) = &previous {
+                    self.publish_changes(&machine.name, previous, state);
+                }
+                CachedStatus {
+                    state: Some(state.clone()),
+                    last_seen: Some(now),
+                    checked_at: now,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                if previous.as_ref().is_none_or(CachedStatus::is_online) {
+                    log::warn!("Machine {} went offline: {}", machine.name, e);
+                    self.events.publish(Event::MachineOffline {
+                        machine: machine.name.clone(),
+                        error: e.to_string(),
+                    });
+                }
+                CachedStatus {
+                    state: previous.as_ref().and_then(|status| status.state.clone()),
+                    last_seen: previous.and_then(|status| status.last_seen),
+                    checked_at: now,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        metrics::gauge!(
+            "bartender_machine_online",
+            if status.is_online() { 1.0 } else { 0.0 },
+            "machine" => machine.name.clone()
+        );
+        statuses.insert(machine.name.clone(), status);
+        result
+    }
+
+    /// Tell listeners how a machine changed since it was last polled
+    fn publish_changes(&self, name: &str, previous: &CachedStatus, state: &MachineResponse) {
+        if !previous.is_online() {
+            log::info!("Machine {} is back online", name);
+            self.events.publish(Event::MachineOnline {
+                machine: name.to_owned(),
+            });
+        }
+
+        let old_slots = previous.state.as_ref().map(|state| &state.slots[..]);
+        for slot in state.slots.iter() {
+            let was_stocked = old_slots
+                .and_then(|slots| slots.iter().find(|old| old.number == slot.number))
+                .map(|old| old.stocked);
+            if was_stocked.is_some_and(|stocked| stocked != slot.stocked) {
+                self.events.publish(match slot.stocked {
+                    true => Event::SlotStocked {
+                        machine: name.to_owned(),
+                        slot: slot.number,
+                    },
+                    false => Event::SlotEmpty {
+                        machine: name.to_owned(),
+                        slot: slot.number,
+                    },
+                });
+            }
+        }
+    }
+
+    /// Get a machine's status, from the cache unless `fresh` is set
+    ///
+    /// A machine that hasn't been polled recently (for example one that was
+    /// just added) is asked directly.
+    pub async fn get_status(
+        &self,
+        machine: &Machine,
+        fresh: bool,
+    ) -> Result<MachineResponse, MachineError> {
+        if !fresh {
+            if let Some(status) = self.cached(&machine.name) {
+                return match status.error {
+                    None => Ok(status.state.unwrap()),
+                    Some(e) => Err(MachineError::Other(e)),
+                };
+            }
+        }
+        self.poll(machine).await
+    }
+
+    /// Everything the poller currently knows, however old
+    pub fn snapshot(&self) -> HashMap<String, CachedStatus> {
+        self.statuses.read().unwrap().clone()
+    }
+
+    /// The cached status of a machine, if it is recent enough to trust
+    pub fn cached(&self, name: &str) -> Option<CachedStatus> {
+        let max_age = chrono::Duration::from_std(self.interval * 3).unwrap();
+        self.statuses
+            .read()
+            .unwrap()
+            .get(name)
+            .filter(|status| Utc::now() - status.checked_at <= max_age)
+            .cloned()
+    }
+}
@@ -0,0 +1,121 @@
+use crate::db;
+use crate::db::models::Machine;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+/// How long a machine has to stay too warm before alerting, in seconds
+pub const DEFAULT_ALERT_DURATION_SECS: i64 = 900;
+
+#[derive(Default)]
+struct AlertState {
+    /// When the machine first went over its threshold
+    warm_since: Option<DateTime<Utc>>,
+    alerted: bool,
+}
+
+/// Records machine temperatures and raises alerts when a fridge stays warm
+///
+/// A machine's threshold is its `max_temp`, falling back to
+/// TEMP_ALERT_THRESHOLD. Machines with neither (like snack) never alert.
+#[derive(Clone)]
+pub struct TemperatureMonitor {
+    pool: Arc<Pool<Postgres>>,
+    http_client: reqwest::Client,
+    default_threshold: Option<f32>,
+    duration: Duration,
+    webhook: Option<String>,
+    alerts: Arc<Mutex<HashMap<i32, AlertState>>>,
+}
+
+impl TemperatureMonitor {
+    pub fn new(pool: Arc<Pool<Postgres>>) -> Self {
+        TemperatureMonitor {
+            pool,
+            http_client: reqwest::Client::new(),
+            default_threshold: env::var("TEMP_ALERT_THRESHOLD")
+                .ok()
+                .and_then(|threshold| threshold.parse().ok()),
+            duration: Duration::seconds(
+                env::var("TEMP_ALERT_DURATION")
+                    .ok()
+                    .and_then(|duration| duration.parse().ok())
+                    .unwrap_or(DEFAULT_ALERT_DURATION_SECS),
+            ),
+            webhook: env::var("TEMP_ALERT_WEBHOOK").ok(),
+            alerts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Store a sample and check it against the machine's threshold
+    pub async fn record(&self, machine: &Machine, temp: f32) {
+        if let Err(e) = db::temperatures::log_sample(&self.pool, machine.id, temp).await {
+            log::warn!("Error logging temperature for {}: {}", machine.name, e);
+        }
+
+        let threshold = match machine.max_temp.or(self.default_threshold) {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let now = Utc::now();
+
+        let message = {
+            let mut alerts = self.alerts.lock().unwrap();
+            let state = alerts.entry(machine.id).or_default();
+            if temp > threshold {
+                let warm_since = *state.warm_since.get_or_insert(now);
+                if !state.alerted && now - warm_since >= self.duration {
+                    state.alerted = true;
+                    log::error!(
+                        "Machine {} has been above {} since {} (now {})",
+                        machine.name,
+                        threshold,
+                        warm_since,
+                        temp
+                    );
+                    Some(json!({
+                        "text": format!(
+                            "{} is too warm: {} (limit {}) since {}",
+                            machine.display_name, temp, threshold, warm_since
+                        ),
+                        "machine": machine.name,
+                        "temp": temp,
+                        "threshold": threshold,
+                        "since": warm_since,
+                        "resolved": false
+                    }))
+                } else {
+                    None
+                }
+            } else {
+                let was_alerted = state.alerted;
+                *state = AlertState::default();
+                if was_alerted {
+                    log::info!("Machine {} has cooled down to {}", machine.name, temp);
+                    Some(json!({
+                        "text": format!("{} has cooled down to {}", machine.display_name, temp),
+                        "machine": machine.name,
+                        "temp": temp,
+                        "threshold": threshold,
+                        "resolved": true
+                    }))
+                } else {
+                    None
+                }
+            }
+        };
+
+        if let (Some(message), Some(webhook)) = (message, &self.webhook) {
+            if let Err(e) = self.http_client.post(webhook).json(&message).send().await {
+                log::error!(
+                    "Error sending temperature alert for {}: {}",
+                    machine.name,
+                    e
+                );
+            }
+        }
+    }
+}
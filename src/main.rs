@@ -1,7 +1,8 @@
 use axum::extract::Extension;
 use axum::http::Method;
+use axum::middleware;
 use axum::{
-    routing::{get, post, put},
+    routing::{delete, get, post, put},
     Router,
 };
 use dotenvy::dotenv;
@@ -14,9 +15,19 @@ use tower::ServiceBuilder;
 use tower_http::cors::{self, CorsLayer, Origin};
 use tower_http::trace::TraceLayer;
 
+use bartender::drop::DropService;
+use bartender::events::EventBus;
 use bartender::ldap::client as ldap_client;
+use bartender::machine::bubbler::BubblerBackend;
+use bartender::machine::reconcile::Reconciler;
+use bartender::machine::simulator::SimulatorBackend;
+use bartender::machine::signing::MachineAuth;
+use bartender::machine::status::StatusCache;
+use bartender::machine::Machines;
 use bartender::oidc::client as oidc_client;
+use bartender::oidc::permissions::Roles;
 use bartender::routes;
+use bartender::telemetry::{self, MeteredBackend};
 
 #[tokio::main]
 async fn main() -> Result<(), sqlx::Error> {
@@ -30,6 +41,9 @@ async fn main() -> Result<(), sqlx::Error> {
     // Initialize tracing with previously set logging levels
     tracing_subscriber::fmt::init();
 
+    // Collect metrics for /metrics
+    let metrics_handle = telemetry::install();
+
     // Connect to Postgres
     let pg_pool = Arc::new(
         PgPoolOptions::new()
@@ -43,6 +57,9 @@ async fn main() -> Result<(), sqlx::Error> {
     let oidc_client = oidc_client::OIDCClient::new();
     info!("OIDC client initialized");
 
+    // Work out what each group is allowed to do
+    let roles = Arc::new(Roles::from_env());
+
     // Create an LDAP client
     let ldap_client = ldap_client::LdapClient::new(
         &env::var("LDAP_BIND_DN").unwrap(),
@@ -51,6 +68,35 @@ async fn main() -> Result<(), sqlx::Error> {
     .await;
     info!("LDAP client initialized");
 
+    // Pick how we talk to the machines
+    let backend: Machines = match env::var("MACHINE_BACKEND").as_deref() {
+        Ok("simulator") => {
+            info!("Using the simulated machine backend");
+            Arc::new(SimulatorBackend::from_env())
+        }
+        _ => Arc::new(BubblerBackend::default()),
+    };
+    let machines: Machines = Arc::new(MeteredBackend::new(backend));
+
+    // Machine changes are pushed to anyone listening on /api/v2/events
+    let events = EventBus::new();
+
+    // Keep machine statuses fresh in the background
+    let statuses = StatusCache::new(pg_pool.clone(), machines.clone(), events.clone());
+    statuses.spawn();
+
+    // Compare slot layouts with what the machines report
+    let reconciler = Reconciler::new(pg_pool.clone(), statuses.clone());
+    reconciler.spawn();
+
+    // Create the drop service shared by every front end
+    let drop_service = DropService::new(
+        pg_pool.clone(),
+        Arc::new(ldap_client.clone()),
+        machines.clone(),
+        events.clone(),
+    );
+
     // Map routes to handlers
     let app = Router::new()
         .route("/", get(routes::compat::root::root))
@@ -69,13 +115,92 @@ async fn main() -> Result<(), sqlx::Error> {
                 .put(routes::compat::items::put_items)
                 .delete(routes::compat::items::delete_items),
         )
+        .route_layer(middleware::from_fn(telemetry::track_requests))
         .nest(
             "/api",
             Router::new().nest(
                 "/v2",
-                Router::new().route("/sms", post(routes::v2::sms::handle)),
+                Router::new()
+                    .route("/sms", post(routes::v2::sms::handle))
+                    .route("/events", get(routes::v2::events::get_events))
+                    .route("/audit", get(routes::v2::audit::get_audit_log))
+                    .route(
+                        "/keys",
+                        get(routes::v2::keys::get_keys).post(routes::v2::keys::create_key),
+                    )
+                    .route("/keys/:id", delete(routes::v2::keys::revoke_key))
+                    .route(
+                        "/holds",
+                        get(routes::v2::holds::get_holds).put(routes::v2::holds::resolve_hold),
+                    )
+                    .route(
+                        "/drops/incidents",
+                        get(routes::v2::drops::get_incidents)
+                            .put(routes::v2::drops::resolve_incident),
+                    )
+                    .route(
+                        "/slots/reconcile",
+                        get(routes::v2::slots::get_reconciliation)
+                            .post(routes::v2::slots::run_reconciliation),
+                    )
+                    .route(
+                        "/machines",
+                        get(routes::v2::machines::get_machines)
+                            .post(routes::v2::machines::create_machine),
+                    )
+                    .route(
+                        "/machines/:name",
+                        put(routes::v2::machines::update_machine)
+                            .delete(routes::v2::machines::delete_machine),
+                    )
+                    .route(
+                        "/machines/status",
+                        get(routes::v2::machines::get_statuses),
+                    )
+                    .route(
+                        "/machines/:name/slots",
+                        get(routes::v2::slots::get_slots)
+                            .post(routes::v2::slots::create_slot)
+                            .put(routes::v2::slots::put_layout),
+                    )
+                    .route(
+                        "/machines/:name/slots/check",
+                        get(routes::v2::slots::check_layout),
+                    )
+                    .route(
+                        "/machines/:name/slots/:number",
+                        put(routes::v2::slots::update_slot).delete(routes::v2::slots::delete_slot),
+                    )
+                    .route(
+                        "/machines/:name/connection",
+                        get(routes::v2::machines::get_connection)
+                            .put(routes::v2::machines::put_connection),
+                    )
+                    .route(
+                        "/machines/:name/maintenance",
+                        get(routes::v2::machines::get_maintenance)
+                            .put(routes::v2::machines::put_maintenance)
+                            .delete(routes::v2::machines::delete_maintenance),
+                    )
+                    .route(
+                        "/machines/:name/temperature",
+                        get(routes::v2::machines::get_temperature)
+                            .put(routes::v2::machines::put_temperature),
+                    )
+                    .route(
+                        "/machines/:name/secret",
+                        post(routes::v2::machines::rotate_secret),
+                    )
+                    .route(
+                        "/users/:uid/transactions",
+                        get(routes::v2::users::get_transactions),
+                    )
+                    .route_layer(middleware::from_fn(telemetry::track_requests)),
             ),
         )
+        .route("/metrics", get(routes::metrics::get_metrics))
+        .route("/healthz", get(routes::health::healthz))
+        .route("/readyz", get(routes::health::readyz))
         .layer(
             ServiceBuilder::new()
                 .layer(TraceLayer::new_for_http())
@@ -90,7 +215,14 @@ async fn main() -> Result<(), sqlx::Error> {
                 )
                 .layer(Extension(ldap_client))
                 .layer(Extension(pg_pool))
-                .layer(Extension(oidc_client)),
+                .layer(Extension(oidc_client))
+                .layer(Extension(roles))
+                .layer(Extension(drop_service))
+                .layer(Extension(statuses))
+                .layer(Extension(reconciler))
+                .layer(Extension(events))
+                .layer(Extension(metrics_handle))
+                .layer(Extension(Arc::new(MachineAuth::from_env()))),
         );
 
     // Bind and serve
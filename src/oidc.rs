@@ -3,6 +3,10 @@ use std::error::Error;
 #[derive(Debug)]
 pub enum OIDCError {
     Unauthorized,
+    /// The token was checked locally and rejected
+    InvalidToken(String),
+    /// The token couldn't be checked locally, so it may still be fine
+    Unverifiable(String),
     ReqwestError(reqwest::Error),
     Unknown,
 }
@@ -13,12 +17,17 @@ impl std::fmt::Display for OIDCError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             OIDCError::Unauthorized => write!(f, "OIDC returned Unauthorized"),
+            OIDCError::InvalidToken(reason) => write!(f, "Invalid token: {reason}"),
+            OIDCError::Unverifiable(reason) => write!(f, "Could not verify token: {reason}"),
             OIDCError::ReqwestError(re) => write!(f, "Reqwest Error: {re}"),
             &OIDCError::Unknown => write!(f, "Unknown OIDC Error"),
         }
     }
 }
 
+pub mod api_key;
 pub mod auth;
+pub mod cache;
 pub mod client;
+pub mod permissions;
 pub mod user;
@@ -0,0 +1,161 @@
+use crate::db;
+use crate::db::api_keys::ApiScope;
+use crate::db::models;
+use crate::machine::signing;
+use crate::oidc::permissions::Permission;
+use axum::http::Method;
+use chrono::Utc;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+
+/// Header integrations send their key in
+pub const API_KEY_HEADER: &str = "X-API-Key";
+/// Every key starts with this, so leaked keys are easy to recognize
+const KEY_MARKER: &str = "bartender";
+
+/// What each scope lets a key do, as routes it may call
+const SCOPE_ROUTES: &[(ApiScope, Method, &str)] = &[
+    (ApiScope::ReadDrinks, Method::GET, "/drinks"),
+    (ApiScope::ReadDrinks, Method::GET, "/items"),
+    (ApiScope::ReadDrinks, Method::GET, "/api/v2/events"),
+    (ApiScope::ReadDrinks, Method::GET, "/api/v2/machines/status"),
+    (
+        ApiScope::ReadDrinks,
+        Method::GET,
+        "/api/v2/machines/:name/slots",
+    ),
+    (
+        ApiScope::ReadDrinks,
+        Method::GET,
+        "/api/v2/machines/:name/maintenance",
+    ),
+    (ApiScope::Drop, Method::POST, "/drinks/drop"),
+    (ApiScope::ManageItems, Method::POST, "/items"),
+    (ApiScope::ManageItems, Method::PUT, "/items"),
+    (ApiScope::ManageItems, Method::DELETE, "/items"),
+    (
+        ApiScope::ReadStats,
+        Method::GET,
+        "/api/v2/users/:uid/transactions",
+    ),
+    (ApiScope::ReadStats, Method::GET, "/api/v2/drops/incidents"),
+    (
+        ApiScope::ReadStats,
+        Method::GET,
+        "/api/v2/machines/:name/temperature",
+    ),
+];
+
+/// The permissions each scope needs for the routes it covers
+const SCOPE_PERMISSIONS: &[(ApiScope, Permission)] = &[
+    (ApiScope::ManageItems, Permission::ManageItems),
+    (ApiScope::ReadStats, Permission::ViewUsers),
+    (ApiScope::ReadStats, Permission::ManageDrops),
+];
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    Malformed,
+    Unknown,
+    Revoked,
+    Expired,
+}
+
+impl std::fmt::Display for ApiKeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ApiKeyError::Malformed => write!(f, "malformed API key"),
+            ApiKeyError::Unknown => write!(f, "unknown API key"),
+            ApiKeyError::Revoked => write!(f, "API key has been revoked"),
+            ApiKeyError::Expired => write!(f, "API key has expired"),
+        }
+    }
+}
+
+/// A freshly minted key, the only time the full key is known
+pub struct NewKey {
+    pub key: String,
+    pub prefix: String,
+    pub hash: String,
+}
+
+#[must_use]
+pub fn generate() -> NewKey {
+    let prefix = hex::encode(rand::thread_rng().gen::<[u8; 4]>());
+    let key = format!("{KEY_MARKER}_{prefix}_{}", signing::generate_secret());
+    NewKey {
+        hash: hash(&key),
+        key,
+        prefix,
+    }
+}
+
+/// Keys are long and random, so a plain hash is enough to store them safely
+fn hash(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Look up a key and make sure it can still be used
+pub async fn authenticate(pool: &Pool<Postgres>, key: &str) -> Result<models::ApiKey, ApiKeyError> {
+    let prefix = match key.split('_').collect::<Vec<_>>()[..] {
+        [KEY_MARKER, prefix, _] => prefix,
+        _ => return Err(ApiKeyError::Malformed),
+    };
+
+    let api_key = db::api_keys::get_api_key_by_prefix(pool, prefix)
+        .await
+        .map_err(|_| ApiKeyError::Unknown)?;
+    // The hashes are compared in full, so timing doesn't give away how much matched
+    let matches = api_key.key_hash.len() == 64
+        && api_key
+            .key_hash
+            .bytes()
+            .zip(hash(key).bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        return Err(ApiKeyError::Unknown);
+    }
+    if api_key.revoked_at.is_some() {
+        return Err(ApiKeyError::Revoked);
+    }
+    if api_key
+        .expires_at
+        .is_some_and(|expires| expires <= Utc::now())
+    {
+        return Err(ApiKeyError::Expired);
+    }
+
+    Ok(api_key)
+}
+
+/// The scopes a key was given, ignoring any this version doesn't know
+#[must_use]
+pub fn scopes(api_key: &models::ApiKey) -> Vec<ApiScope> {
+    api_key
+        .scopes
+        .iter()
+        .filter_map(|scope| ApiScope::parse(scope))
+        .collect()
+}
+
+/// What a key with `scopes` may do once a route has let it in
+#[must_use]
+pub fn permissions(scopes: &[ApiScope]) -> Box<[Permission]> {
+    SCOPE_PERMISSIONS
+        .iter()
+        .filter(|(scope, _)| scopes.contains(scope))
+        .map(|(_, permission)| *permission)
+        .collect()
+}
+
+/// Whether any of `scopes` covers calling `route` with `method`
+#[must_use]
+pub fn allows(scopes: &[ApiScope], method: &Method, route: &str) -> bool {
+    SCOPE_ROUTES
+        .iter()
+        .any(|(scope, allowed_method, allowed_route)| {
+            scopes.contains(scope) && allowed_method == method && *allowed_route == route
+        })
+}
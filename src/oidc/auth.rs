@@ -1,17 +1,27 @@
+use crate::db;
 use crate::ldap::client::LdapClient;
+use crate::machine::signing::{
+    self, MachineAuth, SignatureError, MACHINE_HEADER, NONCE_HEADER, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
 
+use super::api_key::{self, API_KEY_HEADER};
 use super::client::OIDCClient;
 use super::user;
 use axum::async_trait;
-use axum::extract::FromRequest;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, MatchedPath, OriginalUri, RequestParts};
 use axum::http::StatusCode;
 use axum::BoxError;
 use serde_json::json;
 use serde::Deserialize;
-use std::env;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
 
 pub struct OIDCAuth(pub user::OIDCUser);
 
+type Rejection = (axum::http::StatusCode, axum::Json<serde_json::Value>);
+
 #[derive(Deserialize, Debug)]
 struct MinimalUserInfo {
     preferred_username: String,
@@ -20,11 +30,11 @@ struct MinimalUserInfo {
 #[async_trait]
 impl<B> FromRequest<B> for OIDCAuth
 where
-    B: axum::body::HttpBody + Send,
+    B: axum::body::HttpBody + From<Bytes> + Send,
     B::Data: Send,
     B::Error: Into<BoxError>,
 {
-    type Rejection = (axum::http::StatusCode, axum::Json<serde_json::Value>);
+    type Rejection = Rejection;
 
     async fn from_request(
         req: &mut axum::extract::RequestParts<B>,
@@ -37,13 +47,14 @@ where
 
         if let Some(header) = auth_header {
             // Get the OIDClient from the request global state
-            let oidc_client: &OIDCClient = &*req.extensions().get().unwrap();
+            let oidc_client: &OIDCClient = req.extensions().get().unwrap();
 
             match oidc_client.validate_token(header).await {
                 Ok(user) => {
                     return Ok(Self(user));
                 }
-                Err(_) => {
+                Err(e) => {
+                    log::debug!("Rejecting token: {}", e);
                     return Err((
                         StatusCode::UNAUTHORIZED,
                         axum::Json(json!({"error": "token invalid or expired"})),
@@ -52,92 +63,42 @@ where
             }
         }
 
+        // Integrations use their own scoped keys
+        let api_key_header = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .map(|value| value.to_str().unwrap_or_default().to_owned());
+        if let Some(key) = api_key_header {
+            return api_key_user(req, &key).await;
+        }
+
+        // Machines that sign their requests say which machine they are
+        if req.headers().contains_key(SIGNATURE_HEADER) {
+            if let Err(e) = verify_machine_signature(req).await {
+                log::warn!("Rejecting signed machine request: {}", e);
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(json!({"error": "invalid machine signature", "message": e.to_string()})),
+                ));
+            }
+            return machine_user(req).await;
+        }
+
         // If there's no "Authorization" header, get the "X-Auth-Token" header
         let secret_header = req
             .headers()
             .get("X-Auth-Token")
             .map(|value| value.to_str().unwrap());
         if let Some(secret) = secret_header {
-            if secret == env::var("MACHINE_SECRET").unwrap() {
-                // If X-User-Info is set
-                let uid_header = req
-                    .headers()
-                    .get("X-User-Info")
-                    .map(|v| v.to_str().unwrap().to_owned())
-                    .and_then(|value| serde_json::from_str::<MinimalUserInfo>(&value).ok());
-                if let Some(user) = uid_header {
-                    let ldap = &mut *req.extensions_mut().get_mut::<LdapClient>().unwrap();
-                    match ldap.get_user(&user.preferred_username).await {
-                        Some(user) => {
-                            return Ok(Self(user::OIDCUser {
-                                name: Some(user.cn),
-                                preferred_username: user.uid,
-                                groups: user.groups.try_into().unwrap(),
-                                drink_balance: user.drinkBalance,
-                            }))
-                        }
-                        None => {
-                            return Err((
-                                StatusCode::UNAUTHORIZED,
-                                axum::Json(json!({"error": "user not found"})),
-                            ));
-                        }
-                    }
-                }
-
-                // Else if X-User-Phone is set
-                let phone_header = req
-                    .headers()
-                    .get("X-User-Phone")
-                    .map(|v| v.to_str().unwrap().to_owned());
-                if let Some(mut phone) = phone_header {
-                    log::debug!("Got SMS from {}", phone);
-
-                    // Remove +1 from numbers
-                    if phone.starts_with('+') {
-                        if !phone.starts_with("+1") {
-                            return Err((
-                                StatusCode::UNAUTHORIZED,
-                                axum::Json(
-                                    json!({"error": "Only US phone numbers are supported for now"}),
-                                ),
-                            ));
-                        } else {
-                            phone = phone.replace("+1", "");
-                        }
-                    }
-
-                    let ldap = &mut *req.extensions_mut().get_mut::<LdapClient>().unwrap();
-                    match ldap.get_user_by_phone(&phone).await {
-                        Some(user) => {
-                            log::info!("Got user {} from phone number {}", user.uid, phone);
-                            return Ok(Self(user::OIDCUser {
-                                name: Some(user.cn),
-                                preferred_username: user.uid,
-                                groups: user.groups.try_into().unwrap(),
-                                drink_balance: user.drinkBalance,
-                            }));
-                        }
-                        None => {
-                            return Err((
-                                StatusCode::UNAUTHORIZED,
-                                axum::Json(json!({
-                                    "error": "invalid user",
-                                    "message": format!("Make sure your phone number is in the format {} on profiles", &phone)
-                                })),
-                            ));
-                        }
-                    }
-                }
-                // If no other identifying information is provided
-                else {
-                    return Ok(Self(user::OIDCUser {
-                        name: Some(String::from("Drink Machine")),
-                        preferred_username: String::from("drink_machine"),
-                        groups: Box::new([String::from("drink")]),
-                        drink_balance: Some(0),
-                    }));
-                }
+            let machine_auth: &Arc<MachineAuth> = req.extensions().get().unwrap();
+            if !machine_auth.legacy {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(json!({"error": "machine requests must be signed"})),
+                ));
+            }
+            if secret == machine_auth.shared_secret {
+                return machine_user(req).await;
             } else {
                 return Err((
                     StatusCode::UNAUTHORIZED,
@@ -152,3 +113,259 @@ where
         ));
     }
 }
+
+/// The user named in X-User-Info, if there is one
+async fn impersonated_user<B: Send>(
+    req: &mut RequestParts<B>,
+) -> Option<Result<OIDCAuth, Rejection>> {
+    let user = req
+        .headers()
+        .get("X-User-Info")
+        .map(|v| v.to_str().unwrap().to_owned())
+        .and_then(|value| serde_json::from_str::<MinimalUserInfo>(&value).ok())?;
+    let ldap = &mut *req.extensions_mut().get_mut::<LdapClient>().unwrap();
+    match ldap.get_user(&user.preferred_username).await {
+        Some(user) => Some(Ok(OIDCAuth(user::OIDCUser {
+            name: Some(user.cn),
+            preferred_username: user.uid,
+            groups: user.groups.into(),
+            drink_balance: user.drinkBalance,
+            key_permissions: None,
+        }))),
+        None => Some(Err((
+            StatusCode::UNAUTHORIZED,
+            axum::Json(json!({"error": "user not found"})),
+        ))),
+    }
+}
+
+/// Work out who an integration's API key is acting as, if the key allows this route
+async fn api_key_user<B: Send>(
+    req: &mut RequestParts<B>,
+    key: &str,
+) -> Result<OIDCAuth, Rejection> {
+    let pool = req
+        .extensions()
+        .get::<Arc<Pool<Postgres>>>()
+        .unwrap()
+        .clone();
+    let api_key = match api_key::authenticate(&pool, key).await {
+        Ok(api_key) => api_key,
+        Err(e) => {
+            log::warn!("Rejecting API key request: {}", e);
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                axum::Json(json!({"error": "invalid API key", "message": e.to_string()})),
+            ));
+        }
+    };
+
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let scopes = api_key::scopes(&api_key);
+    if !api_key::allows(&scopes, req.method(), &route) {
+        log::warn!(
+            "Rejecting API key {} ({}) for {} {}, not in its scopes",
+            api_key.prefix,
+            api_key.name,
+            req.method(),
+            route
+        );
+        return Err((
+            StatusCode::FORBIDDEN,
+            axum::Json(json!({"error": "API key does not have a scope for this route"})),
+        ));
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = db::api_keys::touch_api_key(&pool, api_key.id).await {
+            log::warn!("Error recording use of API key {}: {}", api_key.prefix, e);
+        }
+    });
+
+    // Dropping spends a real user's credits, so the key has to say whose
+    if route == "/drinks/drop" {
+        return match impersonated_user(req).await {
+            Some(user) => user,
+            None => Err((
+                StatusCode::BAD_REQUEST,
+                axum::Json(json!({"error": "X-User-Info is required to drop with an API key"})),
+            )),
+        };
+    }
+
+    // Keys aren't in any groups, so they only get what their scopes grant
+    Ok(OIDCAuth(user::OIDCUser {
+        name: Some(api_key.name.clone()),
+        preferred_username: format!("apikey:{}", api_key.name),
+        groups: Box::new([]),
+        drink_balance: None,
+        key_permissions: Some(api_key::permissions(&scopes)),
+    }))
+}
+
+/// Work out who a trusted machine is acting for
+async fn machine_user<B: Send>(req: &mut RequestParts<B>) -> Result<OIDCAuth, Rejection> {
+    // If X-User-Info is set
+    if let Some(user) = impersonated_user(req).await {
+        return user;
+    }
+
+    // Else if X-User-IButton is set, from a machine's iButton reader
+    let ibutton_header = req
+        .headers()
+        .get("X-User-IButton")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    if let Some(ibutton) = ibutton_header {
+        // It ends up in an LDAP filter, so only the letters and digits an ID is made of
+        if ibutton.is_empty() || !ibutton.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                axum::Json(json!({"error": "invalid iButton"})),
+            ));
+        }
+
+        let ldap = &mut *req.extensions_mut().get_mut::<LdapClient>().unwrap();
+        match ldap.get_user_by_ibutton(&ibutton).await {
+            Some(user) => {
+                log::info!("Got user {} from their iButton", user.uid);
+                return Ok(OIDCAuth(user::OIDCUser {
+                    name: Some(user.cn),
+                    preferred_username: user.uid,
+                    groups: user.groups.into(),
+                    drink_balance: user.drinkBalance,
+                    key_permissions: None,
+                }));
+            }
+            None => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(json!({
+                        "error": "invalid user",
+                        "message": "The provided iButton value does not belong to any user."
+                    })),
+                ));
+            }
+        }
+    }
+
+    // Else if X-User-Phone is set
+    let phone_header = req
+        .headers()
+        .get("X-User-Phone")
+        .map(|v| v.to_str().unwrap().to_owned());
+    if let Some(mut phone) = phone_header {
+        log::debug!("Got SMS from {}", phone);
+
+        // Remove +1 from numbers
+        if phone.starts_with('+') {
+            if !phone.starts_with("+1") {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(
+                        json!({"error": "Only US phone numbers are supported for now"}),
+                    ),
+                ));
+            } else {
+                phone = phone.replace("+1", "");
+            }
+        }
+
+        let ldap = &mut *req.extensions_mut().get_mut::<LdapClient>().unwrap();
+        match ldap.get_user_by_phone(&phone).await {
+            Some(user) => {
+                log::info!("Got user {} from phone number {}", user.uid, phone);
+                return Ok(OIDCAuth(user::OIDCUser {
+                    name: Some(user.cn),
+                    preferred_username: user.uid,
+                    groups: user.groups.into(),
+                    drink_balance: user.drinkBalance,
+                    key_permissions: None,
+                }));
+            }
+            None => {
+                return Err((
+                    StatusCode::UNAUTHORIZED,
+                    axum::Json(json!({
+                        "error": "invalid user",
+                        "message": format!("Make sure your phone number is in the format {} on profiles", &phone)
+                    })),
+                ));
+            }
+        }
+    }
+
+    // If no other identifying information is provided
+    Ok(OIDCAuth(user::OIDCUser {
+        name: Some(String::from("Drink Machine")),
+        preferred_username: String::from("drink_machine"),
+        groups: Box::new([String::from("drink")]),
+        drink_balance: Some(0),
+        key_permissions: None,
+    }))
+}
+
+/// Check a machine's request signature against its secrets
+async fn verify_machine_signature<B>(req: &mut RequestParts<B>) -> Result<(), SignatureError>
+where
+    B: axum::body::HttpBody + From<Bytes> + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let name = signing_header(req, MACHINE_HEADER)?;
+    let timestamp = signing_header(req, TIMESTAMP_HEADER)?
+        .parse::<i64>()
+        .map_err(|_| SignatureError::Missing(TIMESTAMP_HEADER))?;
+    let nonce = signing_header(req, NONCE_HEADER)?;
+    let signatures = signing_header(req, SIGNATURE_HEADER)?;
+
+    let pool = req
+        .extensions()
+        .get::<Arc<Pool<Postgres>>>()
+        .unwrap()
+        .clone();
+    let machine = db::machines::get_any_machine(&pool, &name)
+        .await
+        .map_err(|_| SignatureError::UnknownMachine(name))?;
+
+    // Nested routers strip their prefix, but the machine signed the full path
+    let uri = match req.extensions().get::<OriginalUri>() {
+        Some(OriginalUri(uri)) => uri.clone(),
+        None => req.uri().clone(),
+    };
+    let path = uri
+        .path_and_query()
+        .map_or_else(|| uri.path(), |path| path.as_str());
+
+    // Put the body back afterwards for the handler's own extractors
+    let body = Bytes::from_request(req).await.unwrap_or_default();
+    *req.body_mut() = Some(B::from(body.clone()));
+
+    let machine_auth = req.extensions().get::<Arc<MachineAuth>>().unwrap();
+    let secrets = signing::machine_secrets(&machine, &machine_auth.shared_secret);
+    if !signing::verify(
+        &secrets,
+        req.method().as_str(),
+        path,
+        timestamp,
+        &nonce,
+        &body,
+        &signatures,
+    ) {
+        return Err(SignatureError::Invalid);
+    }
+    // Only genuine requests use up a nonce
+    machine_auth.replay.check(&machine.name, timestamp, &nonce)
+}
+
+fn signing_header<B>(req: &RequestParts<B>, name: &'static str) -> Result<String, SignatureError> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .ok_or(SignatureError::Missing(name))
+}
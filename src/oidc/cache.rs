@@ -0,0 +1,193 @@
+use super::user::OIDCUser;
+use chrono::Utc;
+use metrics::increment_counter;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long a valid token is trusted without checking it again, in seconds
+const DEFAULT_TOKEN_CACHE_TTL_SECS: u64 = 300;
+/// How long a rejected token stays rejected, in seconds
+const DEFAULT_NEGATIVE_CACHE_TTL_SECS: u64 = 30;
+/// How long a token only userinfo vouched for is trusted, in seconds, since
+/// userinfo doesn't say when it expires or gets revoked
+const DEFAULT_USERINFO_CACHE_TTL_SECS: u64 = 30;
+/// Expired entries are swept out once the cache gets this big
+const MAX_CACHED_TOKENS: usize = 10_000;
+
+enum Entry {
+    Valid(OIDCUser),
+    Rejected,
+}
+
+struct CachedToken {
+    entry: Entry,
+    expires: Instant,
+}
+
+/// What the cache knows about a token
+pub enum Lookup {
+    Valid(OIDCUser),
+    Rejected,
+    Miss,
+}
+
+/// Remembers recent token checks, keyed by a hash of the token
+///
+/// Valid tokens are never kept past their own expiry, and only tokens that
+/// were definitely rejected are cached as rejected, not ones that failed to
+/// verify because the IdP was down.
+#[derive(Clone)]
+pub struct TokenCache {
+    ttl: Duration,
+    negative_ttl: Duration,
+    userinfo_ttl: Duration,
+    tokens: Arc<RwLock<HashMap<String, CachedToken>>>,
+}
+
+impl TokenCache {
+    #[must_use]
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: u64| {
+            Duration::from_secs(
+                env::var(name)
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(default),
+            )
+        };
+        TokenCache {
+            ttl: var("OIDC_CACHE_TTL", DEFAULT_TOKEN_CACHE_TTL_SECS),
+            negative_ttl: var("OIDC_NEGATIVE_CACHE_TTL", DEFAULT_NEGATIVE_CACHE_TTL_SECS),
+            userinfo_ttl: var("OIDC_USERINFO_CACHE_TTL", DEFAULT_USERINFO_CACHE_TTL_SECS),
+            tokens: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn get(&self, token: &str) -> Lookup {
+        let tokens = self.tokens.read().unwrap();
+        let lookup = match tokens.get(&hash(token)) {
+            Some(cached) if cached.expires > Instant::now() => match &cached.entry {
+                Entry::Valid(user) => Lookup::Valid(user.clone()),
+                Entry::Rejected => Lookup::Rejected,
+            },
+            _ => Lookup::Miss,
+        };
+
+        let result = match lookup {
+            Lookup::Valid(_) => "hit",
+            Lookup::Rejected => "negative_hit",
+            Lookup::Miss => "miss",
+        };
+        increment_counter!("bartender_token_cache_total", "result" => result);
+        lookup
+    }
+
+    /// Remember a valid token, until `exp` (in seconds since the epoch) at the latest
+    pub fn insert_valid(&self, token: &str, user: OIDCUser, exp: i64) {
+        self.insert(token, Entry::Valid(user), self.ttl.min(remaining(exp)));
+    }
+
+    /// Remember a token userinfo accepted, briefly, and never past the `exp` it
+    /// claims when it has one
+    pub fn insert_userinfo(&self, token: &str, user: OIDCUser, exp: Option<i64>) {
+        let ttl = exp.map_or(self.userinfo_ttl, |exp| {
+            self.userinfo_ttl.min(remaining(exp))
+        });
+        self.insert(token, Entry::Valid(user), ttl);
+    }
+
+    pub fn insert_rejected(&self, token: &str) {
+        self.insert(token, Entry::Rejected, self.negative_ttl);
+    }
+
+    fn insert(&self, token: &str, entry: Entry, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut tokens = self.tokens.write().unwrap();
+        if tokens.len() >= MAX_CACHED_TOKENS {
+            tokens.retain(|_, cached| cached.expires > now);
+            if tokens.len() >= MAX_CACHED_TOKENS {
+                log::warn!("Token cache is full of live tokens, clearing it");
+                tokens.clear();
+            }
+        }
+        tokens.insert(
+            hash(token),
+            CachedToken {
+                entry,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// Time left until `exp`, in seconds since the epoch
+fn remaining(exp: i64) -> Duration {
+    Duration::from_secs((exp - Utc::now().timestamp()).max(0) as u64)
+}
+
+/// Tokens are credentials, so only their hashes are kept around
+fn hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> TokenCache {
+        TokenCache {
+            ttl: Duration::from_secs(300),
+            negative_ttl: Duration::from_secs(30),
+            userinfo_ttl: Duration::from_secs(30),
+            tokens: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn user() -> OIDCUser {
+        OIDCUser {
+            name: None,
+            preferred_username: String::from("test"),
+            groups: Box::new([]),
+            drink_balance: None,
+            key_permissions: None,
+        }
+    }
+
+    fn ttl(cache: &TokenCache, token: &str) -> Option<Duration> {
+        let tokens = cache.tokens.read().unwrap();
+        tokens
+            .get(&hash(token))
+            .map(|cached| cached.expires - Instant::now())
+    }
+
+    #[test]
+    fn userinfo_tokens_are_only_trusted_briefly() {
+        let cache = cache();
+        let exp = Utc::now().timestamp() + 3600;
+        cache.insert_valid("verified", user(), exp);
+        cache.insert_userinfo("opaque", user(), None);
+        cache.insert_userinfo("claims", user(), Some(exp));
+
+        assert!(ttl(&cache, "verified").unwrap() > Duration::from_secs(60));
+        assert!(ttl(&cache, "opaque").unwrap() <= Duration::from_secs(30));
+        assert!(ttl(&cache, "claims").unwrap() <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn expired_tokens_are_not_cached() {
+        let cache = cache();
+        let exp = Utc::now().timestamp() - 60;
+        cache.insert_valid("verified", user(), exp);
+        cache.insert_userinfo("claims", user(), Some(exp));
+
+        assert!(matches!(cache.get("verified"), Lookup::Miss));
+        assert!(matches!(cache.get("claims"), Lookup::Miss));
+    }
+}
@@ -1,30 +1,248 @@
+use super::cache::{Lookup, TokenCache};
 use super::{user::OIDCUser, OIDCError};
-use std::time::Duration;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::env;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
+/// The CSH realm, used unless OIDC_ISSUER says otherwise
+const DEFAULT_ISSUER: &str = "https://sso.csh.rit.edu/auth/realms/csh";
+/// How long fetched signing keys are trusted before fetching them again, in seconds
+const DEFAULT_JWKS_MAX_AGE_SECS: u64 = 3600;
+/// Tokens signed with an unknown key trigger a refetch, but no more often than this
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+/// Only asymmetric algorithms, so nobody can sign tokens with the public key
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+/// The parts of the issuer's discovery document we use
+#[derive(Debug, Clone, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    jwks_uri: String,
+    userinfo_endpoint: String,
+}
+
+struct CachedKeys {
+    keys: JwkSet,
+    fetched_at: Instant,
+}
+
+type Claims = serde_json::Map<String, serde_json::Value>;
+
+/// Which claims hold the user's details, since every IdP names them differently
+///
+/// Names containing dots that aren't claims themselves are followed as paths
+/// into nested claims, like `realm_access.roles`.
+#[derive(Debug, Clone)]
+struct ClaimNames {
+    username: String,
+    groups: String,
+    balance: String,
+}
+
+impl ClaimNames {
+    fn from_env() -> Self {
+        ClaimNames {
+            username: env::var("OIDC_USERNAME_CLAIM")
+                .unwrap_or_else(|_| String::from("preferred_username")),
+            groups: env::var("OIDC_GROUPS_CLAIM").unwrap_or_else(|_| String::from("groups")),
+            balance: env::var("OIDC_BALANCE_CLAIM")
+                .unwrap_or_else(|_| String::from("drink_balance")),
+        }
+    }
+
+    /// Build a user out of token or userinfo claims, if it has a username
+    fn user(&self, claims: &Claims) -> Option<OIDCUser> {
+        let preferred_username = claim(claims, &self.username)?.as_str()?.to_owned();
+        let groups = claim(claims, &self.groups)
+            .and_then(serde_json::Value::as_array)
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(serde_json::Value::as_str)
+                    // Keycloak can send full group paths
+                    .map(|group| group.trim_start_matches('/').to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let drink_balance = claim(claims, &self.balance).and_then(|balance| {
+            balance
+                .as_i64()
+                .or_else(|| balance.as_str().and_then(|balance| balance.parse().ok()))
+        });
+
+        Some(OIDCUser {
+            name: claim(claims, "name")
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_owned),
+            preferred_username,
+            groups,
+            drink_balance,
+            key_permissions: None,
+        })
+    }
+}
+
+fn claim<'a>(claims: &'a Claims, name: &str) -> Option<&'a serde_json::Value> {
+    claims.get(name).or_else(|| {
+        let mut path = name.split('.');
+        let first = claims.get(path.next()?)?;
+        path.try_fold(first, |value, key| value.get(key))
+    })
+}
+
+/// The expiry a token claims, without checking its signature
+///
+/// Only good for cutting short how long a token is trusted, never for trusting it.
+fn unverified_expiry(token: &str) -> Option<i64> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.required_spec_claims.clear();
+    jsonwebtoken::decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)
+        .ok()?
+        .claims
+        .get("exp")?
+        .as_i64()
+}
+
+/// Verifies access tokens from the identity provider
+///
+/// Tokens are checked locally against the issuer's published keys, which are
+/// found through OIDC discovery and cached. The userinfo endpoint is only asked
+/// when OIDC_USERINFO_FALLBACK is set and a token can't be checked locally.
 #[derive(Clone)]
 pub struct OIDCClient {
     http_client: reqwest::Client,
+    issuer: String,
+    claims: ClaimNames,
+    audience: Option<String>,
+    userinfo_fallback: bool,
+    jwks_max_age: Duration,
+    provider: Arc<RwLock<Option<ProviderMetadata>>>,
+    keys: Arc<RwLock<Option<CachedKeys>>>,
+    /// Held while fetching keys, so a rotation only causes one fetch
+    refresh: Arc<tokio::sync::Mutex<()>>,
+    cache: TokenCache,
 }
 
 impl OIDCClient {
     #[must_use]
     pub fn new() -> Self {
+        let audience = env::var("OIDC_AUDIENCE").ok();
+        if audience.is_none() {
+            log::warn!("OIDC_AUDIENCE is not set, token audiences will not be checked");
+        }
+        let issuer = env::var("OIDC_ISSUER")
+            .map(|issuer| issuer.trim_end_matches('/').to_owned())
+            .unwrap_or_else(|_| String::from(DEFAULT_ISSUER));
+        log::info!("Using OIDC issuer {}", issuer);
         OIDCClient {
             http_client: reqwest::Client::new(),
+            issuer,
+            claims: ClaimNames::from_env(),
+            audience,
+            userinfo_fallback: env::var("OIDC_USERINFO_FALLBACK")
+                .is_ok_and(|fallback| fallback == "true"),
+            jwks_max_age: Duration::from_secs(
+                env::var("OIDC_JWKS_MAX_AGE")
+                    .ok()
+                    .and_then(|max_age| max_age.parse().ok())
+                    .unwrap_or(DEFAULT_JWKS_MAX_AGE_SECS),
+            ),
+            provider: Arc::new(RwLock::new(None)),
+            keys: Arc::new(RwLock::new(None)),
+            refresh: Arc::new(tokio::sync::Mutex::new(())),
+            cache: TokenCache::from_env(),
         }
     }
 
     pub async fn validate_token(&self, token: &str) -> Result<OIDCUser, OIDCError> {
-        let formatted_token = if token.starts_with("Bearer") {
-            token.to_string()
-        } else {
-            format!("Bearer {token}")
+        let token = token.strip_prefix("Bearer").unwrap_or(token).trim();
+
+        match self.cache.get(token) {
+            Lookup::Valid(user) => return Ok(user),
+            Lookup::Rejected => return Err(OIDCError::Unauthorized),
+            Lookup::Miss => {}
+        }
+
+        let result = match self.validate_locally(token).await {
+            Err(OIDCError::Unverifiable(reason)) if self.userinfo_fallback => {
+                log::debug!("Falling back to userinfo: {}", reason);
+                self.userinfo(token).await.inspect(|user| {
+                    self.cache
+                        .insert_userinfo(token, user.clone(), unverified_expiry(token));
+                })
+            }
+            result => result.map(|(user, exp)| {
+                self.cache.insert_valid(token, user.clone(), exp);
+                user
+            }),
         };
+        if let Err(OIDCError::Unauthorized | OIDCError::InvalidToken(_)) = result {
+            self.cache.insert_rejected(token);
+        }
+        result
+    }
+
+    /// Check a token's signature and claims, returning the user and its expiry
+    async fn validate_locally(&self, token: &str) -> Result<(OIDCUser, i64), OIDCError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| OIDCError::Unverifiable(format!("not a JWT: {e}")))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(OIDCError::InvalidToken(format!(
+                "{:?} is not an accepted algorithm",
+                header.alg
+            )));
+        }
+
+        let provider = self.provider().await?;
+        let jwk = self.find_key(header.kid.as_deref()).await?;
+        if jwk.common.algorithm.is_some_and(|alg| alg != header.alg) {
+            return Err(OIDCError::InvalidToken(format!(
+                "{:?} does not match the signing key",
+                header.alg
+            )));
+        }
+        let key = DecodingKey::from_jwk(&jwk)
+            .map_err(|e| OIDCError::Unverifiable(format!("unusable signing key: {e}")))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = 30;
+        validation.set_issuer(&[&provider.issuer]);
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
 
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|e| OIDCError::InvalidToken(e.to_string()))?
+            .claims;
+        let user = self.claims.user(&claims).ok_or_else(|| {
+            OIDCError::Unverifiable(format!("token has no {} claim", self.claims.username))
+        })?;
+        // Validation already made sure there is one
+        let exp = claims["exp"].as_i64().unwrap_or_default();
+        Ok((user, exp))
+    }
+
+    /// Ask the issuer who a token belongs to
+    async fn userinfo(&self, token: &str) -> Result<OIDCUser, OIDCError> {
+        let provider = self.provider().await?;
         let res = self
             .http_client
-            .get("https://sso.csh.rit.edu/auth/realms/csh/protocol/openid-connect/userinfo")
-            .header("Authorization", &formatted_token)
+            .get(&provider.userinfo_endpoint)
+            .bearer_auth(token)
             .timeout(Duration::from_secs(5))
             .send()
             .await;
@@ -32,8 +250,8 @@ impl OIDCClient {
         match res {
             Ok(response) => {
                 if response.status().is_success() {
-                    match response.json::<OIDCUser>().await {
-                        Ok(user) => Ok(user),
+                    match response.json::<Claims>().await {
+                        Ok(claims) => self.claims.user(&claims).ok_or(OIDCError::Unknown),
                         Err(e) => Err(OIDCError::ReqwestError(e)),
                     }
                 } else if response.status().is_client_error() {
@@ -45,6 +263,114 @@ impl OIDCClient {
             Err(e) => Err(OIDCError::ReqwestError(e)),
         }
     }
+
+    /// The issuer's discovery document, fetched once and kept
+    async fn provider(&self) -> Result<ProviderMetadata, OIDCError> {
+        if let Some(provider) = self.provider.read().unwrap().as_ref() {
+            return Ok(provider.clone());
+        }
+
+        let provider = self
+            .http_client
+            .get(self.discovery_url())
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| OIDCError::Unverifiable(format!("discovery failed: {e}")))?
+            .json::<ProviderMetadata>()
+            .await
+            .map_err(|e| OIDCError::Unverifiable(format!("discovery failed: {e}")))?;
+        // Anyone could publish a discovery document, it has to be the issuer's own
+        if provider.issuer.trim_end_matches('/') != self.issuer {
+            return Err(OIDCError::Unverifiable(format!(
+                "discovery returned issuer {}, expected {}",
+                provider.issuer, self.issuer
+            )));
+        }
+
+        *self.provider.write().unwrap() = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Find the key a token was signed with, refetching the keys if it's new
+    async fn find_key(&self, kid: Option<&str>) -> Result<Jwk, OIDCError> {
+        if let Some(jwk) = self.cached_key(kid, self.jwks_max_age) {
+            return Ok(jwk);
+        }
+
+        let _refresh = self.refresh.lock().await;
+        // Someone else may have refreshed while we waited
+        if let Some(jwk) = self.cached_key(kid, self.jwks_max_age) {
+            return Ok(jwk);
+        }
+        let recently_fetched = self
+            .keys
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|cached| cached.fetched_at.elapsed() < JWKS_MIN_REFRESH);
+        if !recently_fetched {
+            if let Err(e) = self.fetch_keys().await {
+                // Stale keys are better than none while the issuer is unreachable
+                return self.cached_key(kid, Duration::MAX).ok_or(e);
+            }
+        }
+
+        self.cached_key(kid, Duration::MAX).ok_or_else(|| {
+            OIDCError::InvalidToken(format!("unknown signing key {}", kid.unwrap_or("(none)")))
+        })
+    }
+
+    fn cached_key(&self, kid: Option<&str>, max_age: Duration) -> Option<Jwk> {
+        let keys = self.keys.read().unwrap();
+        let cached = keys
+            .as_ref()
+            .filter(|cached| cached.fetched_at.elapsed() < max_age)?;
+        match kid {
+            Some(kid) => cached.keys.find(kid).cloned(),
+            // Without a kid, only an issuer with a single key is unambiguous
+            None if cached.keys.keys.len() == 1 => cached.keys.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn fetch_keys(&self) -> Result<(), OIDCError> {
+        let provider = self.provider().await?;
+        log::info!("Fetching signing keys from {}", provider.jwks_uri);
+        let keys = self
+            .http_client
+            .get(&provider.jwks_uri)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| OIDCError::Unverifiable(format!("fetching signing keys failed: {e}")))?
+            .json::<JwkSet>()
+            .await
+            .map_err(|e| OIDCError::Unverifiable(format!("fetching signing keys failed: {e}")))?;
+
+        *self.keys.write().unwrap() = Some(CachedKeys {
+            keys,
+            fetched_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    fn discovery_url(&self) -> String {
+        format!("{}/.well-known/openid-configuration", self.issuer)
+    }
+
+    /// Check that the identity provider is reachable
+    pub async fn ping(&self) -> Result<(), reqwest::Error> {
+        self.http_client
+            .get(self.discovery_url())
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
 
 impl Default for OIDCClient {
@@ -52,3 +378,30 @@ impl Default for OIDCClient {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    #[test]
+    fn unverified_expiry_reads_exp_from_any_jwt() {
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "sub": "test", "exp": 1_700_000_000 }),
+            &EncodingKey::from_secret(b"not the issuer's key"),
+        )
+        .unwrap();
+        assert_eq!(unverified_expiry(&token), Some(1_700_000_000));
+
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({ "sub": "test" }),
+            &EncodingKey::from_secret(b"not the issuer's key"),
+        )
+        .unwrap();
+        assert_eq!(unverified_expiry(&token), None);
+        assert_eq!(unverified_expiry("opaque-token"), None);
+    }
+}
@@ -0,0 +1,302 @@
+use super::auth::OIDCAuth;
+use super::user::OIDCUser;
+use axum::async_trait;
+use axum::body::Bytes;
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::StatusCode;
+use axum::{BoxError, Json};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+type Rejection = (StatusCode, Json<serde_json::Value>);
+
+/// Something a user can be allowed to do, granted through their groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Permission {
+    /// See other users, their credits and their transactions
+    ViewUsers,
+    /// Set anyone's drink balance
+    EditCredits,
+    /// Add, change and remove items
+    ManageItems,
+    /// Change what's in a slot, whether it's active and how many are left, through
+    /// the original `/slots` API
+    ManageSlots,
+    /// Add, change and remove machines, their connections and slots
+    ManageMachines,
+    /// Settle drop incidents and unreconciled holds
+    ManageDrops,
+    /// Read the audit log
+    ViewAudit,
+    /// Mint and revoke API keys
+    ManageKeys,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 8] = [
+        Permission::ViewUsers,
+        Permission::EditCredits,
+        Permission::ManageItems,
+        Permission::ManageSlots,
+        Permission::ManageMachines,
+        Permission::ManageDrops,
+        Permission::ViewAudit,
+        Permission::ManageKeys,
+    ];
+
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ViewUsers => "view_users",
+            Permission::EditCredits => "edit_credits",
+            Permission::ManageItems => "manage_items",
+            Permission::ManageSlots => "manage_slots",
+            Permission::ManageMachines => "manage_machines",
+            Permission::ManageDrops => "manage_drops",
+            Permission::ViewAudit => "view_audit",
+            Permission::ManageKeys => "manage_keys",
+        }
+    }
+
+    #[must_use]
+    pub fn parse(permission: &str) -> Option<Self> {
+        Permission::ALL
+            .into_iter()
+            .find(|known| known.as_str() == permission)
+    }
+}
+
+/// Which permissions each group grants
+#[derive(Debug)]
+pub struct Roles {
+    groups: HashMap<String, HashSet<Permission>>,
+}
+
+impl Default for Roles {
+    /// The same checks as before permissions could be configured. Drink members run
+    /// the machines day to day, and drink admins set them up. Items and the original
+    /// slot API were only ever checked against the drink group.
+    fn default() -> Self {
+        Roles::parse(
+            "drink=view_users,edit_credits,manage_items,manage_slots,manage_drops;\
+             drink_admin=view_users,edit_credits,manage_machines,manage_drops,view_audit,manage_keys",
+        )
+        .unwrap()
+    }
+}
+
+impl Roles {
+    /// Read the mapping from GROUP_PERMISSIONS, or use the defaults when it's unset
+    ///
+    /// # Panics
+    ///
+    /// When GROUP_PERMISSIONS can't be parsed, rather than guess at what was meant.
+    #[must_use]
+    pub fn from_env() -> Self {
+        let roles = match env::var("GROUP_PERMISSIONS") {
+            Ok(config) => Roles::parse(&config)
+                .unwrap_or_else(|e| panic!("GROUP_PERMISSIONS is invalid: {e}")),
+            Err(_) => Roles::default(),
+        };
+        for (group, permissions) in &roles.groups {
+            log::info!(
+                "Group {} grants {}",
+                group,
+                permissions
+                    .iter()
+                    .map(|permission| permission.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        roles
+    }
+
+    /// Parse `group=permission,permission;group=*`, where `*` grants everything
+    pub fn parse(config: &str) -> Result<Self, String> {
+        let mut groups: HashMap<String, HashSet<Permission>> = HashMap::new();
+        for entry in config.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (group, permissions) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("'{entry}' should look like group=permission,..."))?;
+            let group = group.trim();
+            if group.is_empty() {
+                return Err(format!("'{entry}' is missing a group"));
+            }
+            let granted = groups.entry(group.to_owned()).or_default();
+            for permission in permissions.split(',').map(str::trim) {
+                if permission == "*" {
+                    granted.extend(Permission::ALL);
+                } else if !permission.is_empty() {
+                    granted.insert(
+                        Permission::parse(permission)
+                            .ok_or_else(|| format!("'{permission}' is not a permission"))?,
+                    );
+                }
+            }
+        }
+        Ok(Roles { groups })
+    }
+
+    #[must_use]
+    pub fn allows(&self, user: &OIDCUser, permission: Permission) -> bool {
+        if let Some(granted) = &user.key_permissions {
+            return granted.contains(&permission);
+        }
+        user.groups.iter().any(|group| {
+            self.groups
+                .get(group)
+                .is_some_and(|granted| granted.contains(&permission))
+        })
+    }
+}
+
+/// The same answer for every request that lacks a permission
+#[must_use]
+pub fn forbidden() -> Rejection {
+    (
+        StatusCode::FORBIDDEN,
+        Json(json!({
+            "error": "User does not have the correct permissions",
+            "errorCode": 403
+        })),
+    )
+}
+
+/// A permission that can be named as a type, for [`Require`]
+pub trait RequiredPermission {
+    const PERMISSION: Permission;
+}
+
+macro_rules! required_permissions {
+    ($($permission:ident),* $(,)?) => {
+        $(
+            #[doc = concat!("Requires [`Permission::", stringify!($permission), "`]")]
+            pub struct $permission;
+
+            impl RequiredPermission for $permission {
+                const PERMISSION: Permission = Permission::$permission;
+            }
+        )*
+    };
+}
+
+required_permissions!(
+    ViewUsers,
+    EditCredits,
+    ManageItems,
+    ManageSlots,
+    ManageMachines,
+    ManageDrops,
+    ViewAudit,
+    ManageKeys,
+);
+
+/// An authenticated user who has been granted `P`, like `Require<ManageItems>`
+pub struct Require<P> {
+    pub user: OIDCUser,
+    permission: PhantomData<fn() -> P>,
+}
+
+#[async_trait]
+impl<B, P> FromRequest<B> for Require<P>
+where
+    B: axum::body::HttpBody + From<Bytes> + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    P: RequiredPermission,
+{
+    type Rejection = Rejection;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let OIDCAuth(user) = OIDCAuth::from_request(req).await?;
+        let roles = req.extensions().get::<Arc<Roles>>().unwrap();
+        if !roles.allows(&user, P::PERMISSION) {
+            log::warn!(
+                "Rejecting {} for {} {}, missing {}",
+                user.preferred_username,
+                req.method(),
+                req.uri().path(),
+                P::PERMISSION.as_str()
+            );
+            return Err(forbidden());
+        }
+        Ok(Require {
+            user,
+            permission: PhantomData,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(groups: &[&str]) -> OIDCUser {
+        OIDCUser {
+            name: None,
+            preferred_username: String::from("test"),
+            groups: groups.iter().map(|group| (*group).to_owned()).collect(),
+            drink_balance: None,
+            key_permissions: None,
+        }
+    }
+
+    #[test]
+    fn defaults_match_the_old_group_checks() {
+        let roles = Roles::default();
+        let drink = user(&["drink"]);
+        let admin = user(&["drink_admin"]);
+        for permission in Permission::ALL {
+            let (drink_allowed, admin_allowed) = match permission {
+                Permission::ViewUsers | Permission::EditCredits | Permission::ManageDrops => {
+                    (true, true)
+                }
+                Permission::ManageItems | Permission::ManageSlots => (true, false),
+                Permission::ManageMachines | Permission::ViewAudit | Permission::ManageKeys => {
+                    (false, true)
+                }
+            };
+            assert_eq!(
+                roles.allows(&drink, permission),
+                drink_allowed,
+                "{permission:?}"
+            );
+            assert_eq!(
+                roles.allows(&admin, permission),
+                admin_allowed,
+                "{permission:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_grants_everything_for_a_star() {
+        let roles = Roles::parse("ops=*; rtp = view_users , view_audit").unwrap();
+        assert!(Permission::ALL
+            .into_iter()
+            .all(|permission| roles.allows(&user(&["ops"]), permission)));
+        assert!(roles.allows(&user(&["rtp"]), Permission::ViewAudit));
+        assert!(!roles.allows(&user(&["rtp"]), Permission::EditCredits));
+        assert!(!roles.allows(&user(&["drink"]), Permission::ViewUsers));
+    }
+
+    #[test]
+    fn parse_rejects_unknown_permissions() {
+        assert!(Roles::parse("drink=view_users,fly").is_err());
+        assert!(Roles::parse("=view_users").is_err());
+        assert!(Roles::parse("drink").is_err());
+    }
+
+    #[test]
+    fn api_keys_ignore_groups() {
+        let roles = Roles::default();
+        let mut key = user(&["drink_admin"]);
+        key.key_permissions = Some(Box::new([Permission::ManageItems]));
+        assert!(roles.allows(&key, Permission::ManageItems));
+        assert!(!roles.allows(&key, Permission::ViewUsers));
+    }
+}
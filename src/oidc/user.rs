@@ -1,12 +1,16 @@
+use super::permissions::Permission;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct OIDCUser {
     pub name: Option<String>,
     pub preferred_username: String,
     pub groups: Box<[String]>,
     pub drink_balance: Option<i64>,
+    /// Set for API keys, which get these from their scopes instead of from groups
+    #[serde(skip)]
+    pub key_permissions: Option<Box<[Permission]>>,
 }
 
 impl OIDCUser {
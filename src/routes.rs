@@ -1,2 +1,4 @@
 pub mod compat;
+pub mod health;
+pub mod metrics;
 pub mod v2;
@@ -1,19 +1,17 @@
 use crate::db;
-use crate::db::models;
-use crate::ldap::client::LdapClient;
-use crate::ldap::user::LdapUserChangeSet;
-use crate::machine;
+use crate::drop::incident::incident_message;
+use crate::drop::{is_slot_empty, DropError, DropRequest, DropService, MAX_IDEMPOTENCY_KEY_LEN};
+use crate::machine::{self, status::StatusCache};
 use crate::oidc::auth::OIDCAuth;
 use crate::{DrinkResponse, Item, Machine, Slot};
 use axum::extract::{Extension, Query};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::IntoResponse;
 use axum::Json;
-use chrono::prelude::*;
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
 use itertools::Itertools;
-use log::{debug, error, info, warn};
+use log::{debug, warn};
 use serde_json::json;
 use sqlx::{Pool, Postgres};
 use std::collections::HashMap;
@@ -23,6 +21,7 @@ use std::sync::Arc;
 pub async fn get_drinks(
     OIDCAuth(_user): OIDCAuth,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(statuses): Extension<StatusCache>,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let machines = match params.get("machine") {
@@ -40,69 +39,57 @@ pub async fn get_drinks(
         }],
         None => db::machines::get_active_machines(&pool).await.unwrap(),
     };
+    let fresh = params.get("fresh").is_some_and(|fresh| fresh == "true");
     let futures: FuturesOrdered<_> = machines
         .iter()
-        .map(|m| machine::get_status(&m.name))
+        .map(|m| statuses.get_status(m, fresh))
         .collect();
-    let machine_states: Vec<Result<machine::MachineResponse, reqwest::Error>> =
+    let machine_states: Vec<Result<machine::MachineResponse, machine::MachineError>> =
         futures.collect().await;
     let slots = db::slots::get_slots_with_items(&pool, None).await.unwrap();
-    let resp =
-        DrinkResponse {
-            machines: machines
-                .iter()
-                .map(|machine| Machine {
-                    id: machine.id,
-                    name: machine.name.clone(),
-                    display_name: machine.display_name.clone(),
-                    is_online: machine_states.iter().any(
-                        |machine_response| match machine_response {
-                            Ok(r) => r.name == machine.name,
-                            _ => false,
+    let resp = DrinkResponse {
+        machines: machines
+            .iter()
+            .map(|machine| Machine {
+                id: machine.id,
+                name: machine.name.clone(),
+                display_name: machine.display_name.clone(),
+                maintenance: machine.maintenance(),
+                is_online: machine_states
+                    .iter()
+                    .any(|machine_response| match machine_response {
+                        Ok(r) => r.name == machine.name,
+                        _ => false,
+                    }),
+                slots: slots
+                    .iter()
+                    .filter(|slot| slot.machine == machine.id)
+                    .map(|slot| Slot {
+                        active: slot.active,
+                        count: slot.count,
+                        empty: is_slot_empty(
+                            slot,
+                            machine_states
+                                .iter()
+                                .filter_map(|response| response.as_ref().ok())
+                                .find(|response| response.name == machine.name),
+                        ),
+                        item: Item {
+                            name: slot.name.clone(),
+                            id: slot.id,
+                            price: slot.price,
                         },
-                    ),
-                    slots: slots
-                        .iter()
-                        .filter(|slot| slot.machine == machine.id)
-                        .map(|slot| Slot {
-                            active: slot.active,
-                            count: slot.count,
-                            empty: match slot.count {
-                                Some(0) => true,
-                                Some(_) => false,
-                                _ => match machine_states.iter().find(|machine_response| {
-                                    match machine_response {
-                                        Ok(response) => response.name == machine.name,
-                                        _ => false,
-                                    }
-                                }) {
-                                    Some(response) => {
-                                        match response.as_ref().unwrap().slots.iter().find(
-                                            |slot_response| slot_response.number == slot.number,
-                                        ) {
-                                            Some(slot_response) => !slot_response.stocked,
-                                            None => true,
-                                        }
-                                    }
-                                    None => true,
-                                },
-                            },
-                            item: Item {
-                                name: slot.name.clone(),
-                                id: slot.id,
-                                price: slot.price,
-                            },
-                            machine: machine.id,
-                            number: slot.number,
-                        })
-                        .collect(),
-                })
-                .collect(),
-            message: format!(
-                "Successfully retrieved machine contents for {}",
-                machines.iter().map(|machine| &machine.name).join(", ")
-            ),
-        };
+                        machine: machine.id,
+                        number: slot.number,
+                    })
+                    .collect(),
+            })
+            .collect(),
+        message: format!(
+            "Successfully retrieved machine contents for {}",
+            machines.iter().map(|machine| &machine.name).join(", ")
+        ),
+    };
     (StatusCode::OK, Json(json!(resp)))
 }
 
@@ -110,8 +97,8 @@ pub async fn get_drinks(
 pub async fn drop(
     OIDCAuth(user): OIDCAuth,
     Json(payload): Json<serde_json::Value>,
-    Extension(pool): Extension<Arc<Pool<Postgres>>>,
-    Extension(mut ldap_client): Extension<LdapClient>,
+    Extension(drop_service): Extension<DropService>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     let user_id = user.preferred_username;
 
@@ -142,115 +129,97 @@ pub async fn drop(
         );
     }
 
-    debug!("Fetching database info for drop request by {}", user_id);
-    let machine = db::machines::get_machine(&pool, payload["machine"].as_str().unwrap()).await;
-    if machine.is_err() {
-        warn!(
-            "Rejecting request from {} to drop a drink, {} is not a valid machine",
-            user_id,
-            payload["machine"].as_str().unwrap()
-        );
+    let slot = match payload["slot"].as_i64().map(i32::try_from) {
+        Some(Ok(slot)) => slot,
+        _ => {
+            warn!(
+                "Rejecting request from {} to drop a drink, {} is not a slot number",
+                user_id, payload["slot"]
+            );
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "message": format!("{} is not a valid slot number", payload["slot"])
+                })),
+            );
+        }
+    };
+
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    if idempotency_key
+        .as_ref()
+        .is_some_and(|key| key.len() > MAX_IDEMPOTENCY_KEY_LEN)
+    {
         return (
             StatusCode::BAD_REQUEST,
             Json(json!({
-                "message":
-                    format!(
-                        "The machine name '{}' is not a valid machine",
-                        payload["machine"].as_str().unwrap()
-                    )
+                "message": format!("Idempotency-Key can be at most {MAX_IDEMPOTENCY_KEY_LEN} characters")
             })),
         );
     }
-    let machine = machine.unwrap();
 
-    let slot =
-        db::slots::get_slot_with_item(&pool, machine.id, payload["slot"].as_i64().unwrap() as i32)
-            .await;
-    if slot.is_err() {
-        warn!(
-            "Rejecting request from {} to drop a drink, machine {} does not have a slot with id {}",
-            user_id,
-            payload["machine"].as_str().unwrap(),
-            payload["slot"].as_i64().unwrap()
-        );
-        return (
+    let request = DropRequest {
+        username: user_id.clone(),
+        machine: payload["machine"].as_str().unwrap_or_default().to_owned(),
+        slot,
+        idempotency_key,
+    };
+
+    match drop_service.drop(&request).await {
+        Ok(outcome) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": "Drop successful!",
+                "drinkBalance": outcome.new_balance,
+                "queuePosition": outcome.queue_position
+            })),
+        ),
+        Err(DropError::InvalidMachine(machine)) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "message": format!("The machine name '{}' is not a valid machine", machine)
+            })),
+        ),
+        Err(DropError::InvalidSlot { machine, slot }) => (
             StatusCode::BAD_REQUEST,
             Json(json!({
                 "message":
                     format!(
                         "The machine '{}' does not have a slot with id '{}'",
-                        payload["machine"].as_str().unwrap(),
-                        payload["slot"].as_i64().unwrap()
+                        machine, slot
                     )
             })),
-        );
-    }
-    let slot = slot.unwrap();
-
-    debug!(
-        "Checking machine {} status for {}",
-        payload["machine"].as_str().unwrap(),
-        user_id
-    );
-    let machine_status = machine::get_status(&machine.name).await;
-    if machine_status.is_err() {
-        warn!(
-            "Rejecting request from {} to drop a drink, machine {} is not online",
-            user_id,
-            payload["machine"].as_str().unwrap(),
-        );
-        return (
+        ),
+        Err(DropError::MachineOffline(machine)) => (
             StatusCode::BAD_REQUEST,
             Json(json!({
-                "message":
-                    format!(
-                        "The machine '{}' is not online",
-                        payload["machine"].as_str().unwrap(),
-                    )
+                "message": format!("The machine '{}' is not online", machine.name)
             })),
-        );
-    }
-    let machine_status = machine_status.unwrap();
-
-    if (machine.name == "snack" && slot.count.unwrap_or(0) < 1)
-        || !slot.active
-        || !(*machine_status.slots.get(slot.number as usize).unwrap()).stocked
-    {
-        warn!(
-            "Rejecting request from {} to drop a drink, machine {} slot {} is empty",
-            user_id,
-            payload["machine"].as_str().unwrap(),
-            payload["slot"].as_i64().unwrap()
-        );
-        return (
+        ),
+        Err(DropError::Maintenance(machine)) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "error": format!("The machine '{}' is down for maintenance", machine.name),
+                "maintenance": machine.maintenance(),
+                "errorCode": 503
+            })),
+        ),
+        Err(DropError::SlotEmpty { .. }) => (
             StatusCode::BAD_REQUEST,
             Json(json!({
-                "message":
-                    "The requested slot is empty!"
+                "message": "The requested slot is empty!"
             })),
-        );
-    }
-
-    debug!("Checking drink credits for {}", user_id);
-    let user = ldap_client.get_user(&user_id).await;
-    if user.is_none() {
-        return (
+        ),
+        Err(DropError::UserNotFound(uid)) => (
             StatusCode::UNAUTHORIZED,
             Json(json!({
-                "message": format!("Could not find an account with the username '{}'", user_id)
+                "message": format!("Could not find an account with the username '{}'", uid)
             })),
-        );
-    }
-    let user = user.unwrap();
-    if user.drinkBalance.unwrap_or(0) < slot.price.into() {
-        warn!(
-            "Rejecting request from {} to drop a drink, insufficient drink balance for {} (has {}, needs {})",
-            user_id,
-            slot.name,
-            user.drinkBalance.unwrap_or(0),
-            slot.price,
-        );
-        return (
+        ),
+        Err(DropError::InsufficientBalance { .. }) => (
             StatusCode::PAYMENT_REQUIRED,
             Json(json!({
                 "message":
@@ -259,135 +228,64 @@ pub async fn drop(
                         user_id
                     )
             })),
-        );
-    }
-
-    debug!(
-        "Sending drop request for machine {} slot {} by {}",
-        payload["machine"].as_str().unwrap(),
-        payload["slot"].as_i64().unwrap(),
-        user_id
-    );
-    let drop_response = machine::drop(&machine.name, slot.number).await;
-
-    if let Err(drop_error) = drop_response {
-        if drop_error.is_connect() {
-            error!(
-                "Error dropping drink for {}, could not connect to machine {}",
-                user_id,
-                payload["machine"].as_str().unwrap(),
-            );
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(
-                    json!({"error": "Could not contact drink machine for drop!", "errorCode": 500}),
-                ),
-            );
-        } else if drop_error.is_timeout() {
-            error!(
-                "Error dropping drink for {}, machine {} timed out",
-                user_id,
-                payload["machine"].as_str().unwrap(),
-            );
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(
-                    json!({"error": "Connection to the drink machine timed out!", "errorCode": 500}),
-                ),
-            );
-        }
-        error!(
-            "Error dropping drink for {}, an unknown error occured occured dropping a drink from machine {} slot {}",
-            user_id,
-            payload["machine"].as_str().unwrap(),
-            payload["slot"].as_i64().unwrap()
-        );
-        return (
+        ),
+        Err(DropError::ConnectionFailed(_)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": "Could not contact drink machine for drop!", "errorCode": 500})),
+        ),
+        Err(DropError::TimedOut { incident, .. }) => (
             StatusCode::INTERNAL_SERVER_ERROR,
-            Json(
-                json!({"error": "An unknown error occured while trying to drop a drink", "errorCode": 500}),
-            ),
-        );
-    }
-
-    let drop_response = drop_response.unwrap();
-
-    if let Err(drop_error) = drop_response.error_for_status_ref() {
-        let drop_content = drop_response.json::<serde_json::Value>().await.unwrap();
-        error!(
-            "Error dropping drink for {}, an error occured occured dropping a drink from machine {} slot {}: {}",
-            user_id,
-            payload["machine"].as_str().unwrap(),
-            payload["slot"].as_i64().unwrap(),
-            drop_content["error"].as_str().unwrap()
-        );
-        return (
-            drop_error.status().unwrap(),
+            Json(json!({
+                "error": "Connection to the drink machine timed out!",
+                "errorCode": 500,
+                "incident": incident,
+                "incidentMessage": incident.map(incident_message)
+            })),
+        ),
+        Err(DropError::MachineError {
+            status,
+            message,
+            incident,
+        }) => (
+            StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
             Json(json!({
                 "error": "Could not access slot for drop!",
-                "message": drop_content["error"].as_str().unwrap(),
-                "errorCode": drop_error.status().unwrap().as_u16()
+                "message": message,
+                "errorCode": status,
+                "incident": incident,
+                "incidentMessage": incident.map(incident_message)
             })),
-        );
-    }
-
-    debug!("Updating drink balance for {}", user_id);
-    let new_balance = user.drinkBalance.unwrap() - slot.price as i64;
-
-    let change_set = LdapUserChangeSet {
-        dn: user.clone().dn,
-        drinkBalance: Some(new_balance),
-        ibutton: None,
-    };
-    ldap_client.update_user(&change_set).await;
-
-    if machine.name == "snack" {
-        if db::slots::update_slot_count(&pool, machine.id, slot.number, slot.count.unwrap_or(1) - 1)
-            .await
-            .is_err()
-        {
-            error!(
-                "Error updating db after drop for {}, could not change machine {} slot {} count {}",
-                user_id,
-                payload["machine"].as_str().unwrap(),
-                payload["slot"].as_i64().unwrap(),
-                slot.count.unwrap_or(1) - 1
-            );
-        }
-        #[allow(clippy::collapsible_if)]
-        if slot.count.unwrap_or(1) == 1 {
-            if db::slots::update_slot_active(&pool, machine.id, slot.number, false)
-                .await
-                .is_err()
-            {
-                error!(
-                    "Error updating db after drop for {}, could not change machine {} slot {} active {}",
-                    user_id,
-                    payload["machine"].as_str().unwrap(),
-                    payload["slot"].as_i64().unwrap(),
-                    false
-                );
-            }
-        }
-    }
-
-    let drop = models::Drop {
-        id: 0,                 // placeholder,
-        timestamp: Utc::now(), // placeholder
-        username: user_id.clone(),
-        machine: machine.id,
-        slot: slot.number,
-        item: slot.id,
-        item_name: slot.name.clone(),
-        item_price: slot.price,
-    };
-    if let Err(e) = db::drops::log_drop(&pool, &drop).await {
-        warn!("Error logging drop: {e}");
+        ),
+        Err(DropError::MachineBusy { machine, position }) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "error": format!("The machine '{}' is busy, please try again", machine.name),
+                "queuePosition": position,
+                "errorCode": 503
+            })),
+        ),
+        Err(DropError::InProgress) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "A drop with this Idempotency-Key is already in progress",
+                "errorCode": 409
+            })),
+        ),
+        Err(DropError::KeyReused) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({
+                "error": "This Idempotency-Key was already used for a different drop",
+                "errorCode": 422
+            })),
+        ),
+        Err(DropError::Unknown { incident, .. }) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({
+                "error": "An unknown error occured while trying to drop a drink",
+                "errorCode": 500,
+                "incident": incident,
+                "incidentMessage": incident.map(incident_message)
+            })),
+        ),
     }
-
-    info!("Successfully dropped {} for {}", slot.name, user_id);
-    (
-        StatusCode::OK,
-        Json(json!({"message": "Drop successful!", "drinkBalance": new_balance})),
-    )
 }
@@ -1,5 +1,6 @@
 use crate::db;
 use crate::oidc::auth::OIDCAuth;
+use crate::oidc::permissions::{ManageItems, Require};
 use axum::extract::Extension;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
@@ -34,20 +35,10 @@ pub async fn get_items(
 
 // POST /items
 pub async fn post_items(
-    OIDCAuth(user): OIDCAuth,
+    _: Require<ManageItems>,
     Json(body): Json<serde_json::Value>,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
 ) -> impl IntoResponse {
-    if !user.has_group("drink") {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
     let name = body["name"].as_str();
     let price = body["price"].as_i64();
 
@@ -113,20 +104,10 @@ pub async fn post_items(
 
 // PUT /items
 pub async fn put_items(
-    OIDCAuth(user): OIDCAuth,
+    _: Require<ManageItems>,
     Json(body): Json<serde_json::Value>,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
 ) -> impl IntoResponse {
-    if !user.has_group("drink") {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
     let id = body["id"].as_i64();
     if id.is_none() {
         return (
@@ -199,20 +180,10 @@ pub async fn put_items(
 
 // DELETE /items
 pub async fn delete_items(
-    OIDCAuth(user): OIDCAuth,
+    _: Require<ManageItems>,
     Json(body): Json<serde_json::Value>,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
 ) -> impl IntoResponse {
-    if !user.has_group("drink") {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
     let id = body["id"].as_i64();
     if id.is_none() {
         return (
@@ -224,7 +195,7 @@ pub async fn delete_items(
     }
     let id = id.unwrap() as i32;
 
-    let item = db::items::get_item(&pool, id as i32).await;
+    let item = db::items::get_item(&pool, id).await;
     if item.is_err() {
         return (
             StatusCode::BAD_REQUEST,
@@ -1,5 +1,6 @@
 use crate::db;
-use crate::oidc::auth::OIDCAuth;
+use crate::events::{Event, EventBus};
+use crate::oidc::permissions::{ManageSlots, Require};
 use axum::extract::Extension;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
@@ -12,20 +13,11 @@ use std::sync::Arc;
 
 // PUT /slots
 pub async fn update_slot_status(
-    OIDCAuth(user): OIDCAuth,
+    Require { user, .. }: Require<ManageSlots>,
     Json(body): Json<serde_json::Value>,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(events): Extension<EventBus>,
 ) -> impl IntoResponse {
-    if !user.has_group("drink") {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
     let user_id = user.preferred_username;
 
     let machine_name = body["machine"].as_str();
@@ -61,10 +53,7 @@ pub async fn update_slot_status(
     let active = body["active"].as_bool();
     let item_id = body["item_id"]
         .as_str()
-        .and_then(|id| match id.parse::<i32>() {
-            Ok(i) => Some(i),
-            Err(_) => None,
-        });
+        .and_then(|id| id.parse::<i32>().ok());
 
     if active.is_none() && item_id.is_none() {
         warn!(
@@ -151,13 +140,13 @@ pub async fn update_slot_status(
     }
 
     if let Some(item_id) = item_id {
-        let item = db::items::get_item(&pool, item_id as i32).await;
+        let item = db::items::get_item(&pool, item_id).await;
         match item {
             Ok(item) => {
                 match db::slots::update_slot_item(&pool, machine.id, slot.number, item.id).await {
                     Ok(_) => {
                         debug!(
-                            "Updated machine {} slot {} item: {} -> {}",
+                            "Updated machine {} slot {} item: {:?} -> {}",
                             machine.name, slot.number, slot.item, item.id
                         );
                     }
@@ -186,7 +175,7 @@ pub async fn update_slot_status(
         }
     }
 
-    if let Some(count) = body["count"].as_str().and_then(|s| Some(s.parse::<i64>().unwrap_or(-1))) {
+    if let Some(count) = body["count"].as_str().map(|s| s.parse::<i64>().unwrap_or(-1)) {
         if count < 0 {
             return (
                 StatusCode::BAD_REQUEST,
@@ -206,6 +195,11 @@ pub async fn update_slot_status(
                     slot.count.unwrap_or(-1),
                     count
                 );
+                events.publish(Event::SlotCount {
+                    machine: machine.name.clone(),
+                    slot: slot.number,
+                    count: count as i32,
+                });
             }
             Err(e) => {
                 error!("Failed to process request from {} to update machine {} slot {}: set count to {}", user_id, machine.name, slot.number, count);
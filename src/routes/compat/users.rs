@@ -1,59 +1,51 @@
+use crate::db;
+use crate::db::models;
+use crate::db::transactions::TransactionKind;
 use crate::ldap::client::LdapClient;
-use crate::ldap::user::LdapUserChangeSet;
+use crate::ldap::BalanceError;
 use crate::oidc::auth::OIDCAuth;
+use crate::oidc::permissions::{forbidden, EditCredits, Permission, Require, Roles, ViewUsers};
 use axum::extract::{Extension, Query};
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
+use chrono::Utc;
 use itertools::Itertools;
+use log::error;
 use serde_json::json;
+use sqlx::{Pool, Postgres};
 use std::collections::HashMap;
+use std::sync::Arc;
 
 // GET /users
 pub async fn get_users(
-    OIDCAuth(user): OIDCAuth,
+    _: Require<ViewUsers>,
     Extension(mut ldap): Extension<LdapClient>,
 ) -> impl IntoResponse {
-    if !(user.has_group("drink") || user.has_group("drink_admin")) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
     let users = ldap._do_not_use_get_all_users().await;
-    return (
+    (
         StatusCode::OK,
         Json(json!({
             "message": format!("Retrieved {} users", users.len()),
             "users": users
         })),
-    );
+    )
 }
 
 // GET /users/credits
 pub async fn get_credits(
     OIDCAuth(user): OIDCAuth,
     Extension(mut ldap): Extension<LdapClient>,
+    Extension(roles): Extension<Arc<Roles>>,
     Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let uid = params.get("uid").map(|id| id.to_owned());
     let ibutton = params.get("ibutton").map(|id| id.to_owned());
 
-    if uid.is_some() {
-        let uid = uid.unwrap();
-
-        if !(user.has_group("drink") || user.has_group("drink_admin")) && user.preferred_username != uid {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "error": "User does not have the correct permissions",
-                    "errorCode": 401
-                })),
-            );
+    if let Some(uid) = uid {
+        // Anyone can look up their own credits
+        if !roles.allows(&user, Permission::ViewUsers) && user.preferred_username != uid {
+            return forbidden();
         }
 
         let user = ldap.get_user(&uid).await;
@@ -77,18 +69,11 @@ pub async fn get_credits(
                 }
             })),
         );
-    } else if ibutton.is_some() {
-        if !(user.has_group("drink") || user.has_group("drink_admin")) {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(json!({
-                    "error": "User does not have the correct permissions",
-                    "errorCode": 401
-                })),
-            );
+    } else if let Some(ibutton) = ibutton {
+        if !roles.allows(&user, Permission::ViewUsers) {
+            return forbidden();
         }
 
-        let ibutton = ibutton.unwrap();
         let user = ldap.get_user_by_ibutton(&ibutton).await;
         if user.is_none() {
             return (
@@ -119,20 +104,12 @@ pub async fn get_credits(
 
 // PUT /users/credits
 pub async fn set_credits(
-    OIDCAuth(user): OIDCAuth,
+    Require { user, .. }: Require<EditCredits>,
     Extension(mut ldap): Extension<LdapClient>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
     Json(body): Json<serde_json::Value>,
 ) -> impl IntoResponse {
-    if !(user.has_group("drink") || user.has_group("drink_admin")) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(json!({
-                "error": "User does not have the correct permissions",
-                "errorCode": 401
-            })),
-        );
-    }
-
+    let actor = user.preferred_username;
     let uid = body["uid"].as_str();
     let new_balance = body["drinkBalance"].as_i64();
 
@@ -174,12 +151,49 @@ pub async fn set_credits(
     let user = user.unwrap();
 
     let old_credits = user.drinkBalance.unwrap_or(0);
-    let change_set = LdapUserChangeSet {
-        dn: user.clone().dn,
-        drinkBalance: Some(new_balance.unwrap()),
-        ibutton: None,
+    match ldap
+        .update_balance(&user.dn, user.drinkBalance, new_balance.unwrap())
+        .await
+    {
+        Ok(()) => {}
+        Err(BalanceError::Conflict) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "error": "Drink balance was changed by another request, please try again",
+                    "errorCode": 409
+                })),
+            );
+        }
+        Err(e) => {
+            error!("Failed to update drink balance for {}: {}", user.uid, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update drink balance",
+                    "errorCode": 500,
+                    "message": "Contact a drink admin"
+                })),
+            );
+        }
+    }
+
+    let transaction = models::CreditTransaction {
+        id: 0,                 // placeholder
+        timestamp: Utc::now(), // placeholder
+        username: user.uid.clone(),
+        kind: TransactionKind::Adjustment.as_str().to_owned(),
+        amount: new_balance.unwrap() - old_credits,
+        balance_before: user.drinkBalance,
+        balance_after: new_balance.unwrap(),
+        actor,
+        reason: body["reason"].as_str().map(str::to_owned),
+        drop_id: None,
     };
-    ldap.update_user(&change_set).await;
+    if let Err(e) = db::transactions::log_transaction(&pool, &transaction).await {
+        error!("Error logging credit transaction for {}: {}", user.uid, e);
+    }
+
     let user = ldap.get_user(uid.unwrap()).await.unwrap();
     let new_balance = user.drinkBalance.unwrap_or(0);
 
@@ -0,0 +1,89 @@
+use crate::ldap::client::LdapClient;
+use crate::machine::status::StatusCache;
+use crate::oidc::client::OIDCClient;
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long a single dependency gets to answer
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Run one dependency check, returning whether it passed and its report
+async fn check<E: Display>(
+    check: impl Future<Output = Result<(), E>>,
+) -> (bool, serde_json::Value) {
+    let start = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("timed out")),
+    };
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => (true, json!({ "status": "up", "latencyMs": latency_ms })),
+        Err(e) => (
+            false,
+            json!({ "status": "down", "latencyMs": latency_ms, "error": e }),
+        ),
+    }
+}
+
+// GET /healthz
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, Json(json!({ "status": "ok" })))
+}
+
+// GET /readyz
+pub async fn readyz(
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(ldap): Extension<LdapClient>,
+    Extension(oidc_client): Extension<OIDCClient>,
+    Extension(statuses): Extension<StatusCache>,
+) -> impl IntoResponse {
+    let ((postgres_ok, postgres), (ldap_ok, ldap), (oidc_ok, oidc)) = tokio::join!(
+        check(async { sqlx::query("SELECT 1").execute(&*pool).await.map(|_| ()) }),
+        check(ldap.ping()),
+        check(oidc_client.ping()),
+    );
+
+    // Machines come and go, so they are reported but don't affect readiness
+    let machines: serde_json::Map<_, _> = statuses
+        .snapshot()
+        .into_iter()
+        .map(|(name, status)| {
+            (
+                name,
+                json!({
+                    "status": if status.is_online() { "up" } else { "down" },
+                    "lastSeen": status.last_seen,
+                    "error": status.error
+                }),
+            )
+        })
+        .collect();
+
+    let ready = postgres_ok && ldap_ok && oidc_ok;
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(json!({
+            "status": if ready { "ok" } else { "unavailable" },
+            "checks": {
+                "postgres": postgres,
+                "ldap": ldap,
+                "oidc": oidc
+            },
+            "machines": machines
+        })),
+    )
+}
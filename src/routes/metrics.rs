@@ -0,0 +1,14 @@
+use crate::ldap::client::LdapClient;
+use crate::telemetry;
+use axum::extract::Extension;
+use axum::response::IntoResponse;
+use metrics_exporter_prometheus::PrometheusHandle;
+
+// GET /metrics
+pub async fn get_metrics(
+    Extension(handle): Extension<PrometheusHandle>,
+    Extension(ldap): Extension<LdapClient>,
+) -> impl IntoResponse {
+    telemetry::record_ldap_pool(&ldap.pool_status());
+    handle.render()
+}
@@ -0,0 +1,63 @@
+use crate::db;
+use crate::oidc::permissions::{Require, ViewAudit};
+use axum::extract::{Extension, Query};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct Page {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+// GET /api/v2/audit
+pub async fn get_audit_log(
+    _: Require<ViewAudit>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Query(params): Query<Page>,
+) -> impl IntoResponse {
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = match (page - 1).checked_mul(per_page) {
+        Some(offset) => offset,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "message": format!("Page {} is out of range", page) })),
+            )
+        }
+    };
+
+    match db::audit::get_audit_log(&pool, per_page, offset).await {
+        Ok(entries) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} audit entries", entries.len()),
+                "page": page,
+                "per_page": per_page,
+                "entries": entries
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error getting audit log: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get audit log",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use crate::db;
+use crate::drop::incident::{IncidentError, Resolution};
+use crate::drop::DropService;
+use crate::oidc::permissions::{ManageDrops, Require};
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct IncidentResolution {
+    id: i32,
+    resolution: String,
+}
+
+// GET /api/v2/drops/incidents
+pub async fn get_incidents(
+    Require { user, .. }: Require<ManageDrops>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+) -> impl IntoResponse {
+    match db::drops::get_incidents(&pool).await {
+        Ok(incidents) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} unresolved incidents", incidents.len()),
+                "incidents": incidents
+            })),
+        ),
+        Err(e) => {
+            log::error!(
+                "Error getting drop incidents for {}: {}",
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get incidents",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/drops/incidents
+pub async fn resolve_incident(
+    Require { user, .. }: Require<ManageDrops>,
+    Extension(drop_service): Extension<DropService>,
+    Json(body): Json<IncidentResolution>,
+) -> impl IntoResponse {
+    let resolution = match body.resolution.as_str() {
+        "refund" => Resolution::Refund,
+        "confirm" => Resolution::Confirm,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "message": "An incident can only be resolved with 'refund' or 'confirm'"
+                })),
+            )
+        }
+    };
+
+    match drop_service
+        .resolve_incident(body.id, resolution, &user.preferred_username)
+        .await
+    {
+        Ok(drop) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Incident {} for {} marked {}", drop.id, drop.username, drop.status),
+                "drop": drop
+            })),
+        ),
+        Err(e @ IncidentError::NotFound(_)) | Err(e @ IncidentError::NotAnIncident { .. }) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": e.to_string() })),
+        ),
+        Err(e) => {
+            log::error!(
+                "Error resolving incident {} for {}: {}",
+                body.id,
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not resolve incident",
+                    "errorCode": 500,
+                    "message": e.to_string()
+                })),
+            )
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use crate::events::EventBus;
+use crate::oidc::auth::OIDCAuth;
+use axum::extract::Extension;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::stream::{self, Stream};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+// GET /api/v2/events
+pub async fn get_events(
+    OIDCAuth(user): OIDCAuth,
+    Extension(events): Extension<EventBus>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    log::debug!("{} subscribed to events", user.preferred_username);
+
+    let stream = stream::unfold(events.subscribe(), |mut receiver| async move {
+        let sse = match receiver.recv().await {
+            Ok(event) => Event::default()
+                .event(event.kind())
+                .json_data(&event)
+                .unwrap(),
+            // Tell a slow listener it missed some, so it can refetch
+            Err(RecvError::Lagged(missed)) => {
+                Event::default().event("lagged").data(missed.to_string())
+            }
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(sse), receiver))
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
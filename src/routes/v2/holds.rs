@@ -0,0 +1,113 @@
+use crate::db;
+use crate::db::holds::HoldStatus;
+use crate::drop::hold::HoldError;
+use crate::drop::DropService;
+use crate::oidc::permissions::{ManageDrops, Require};
+use axum::extract::{Extension, Query};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct HoldQuery {
+    status: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HoldResolution {
+    id: i32,
+    status: String,
+}
+
+// GET /api/v2/holds
+pub async fn get_holds(
+    Require { user, .. }: Require<ManageDrops>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Query(params): Query<HoldQuery>,
+) -> impl IntoResponse {
+    let status = params
+        .status
+        .unwrap_or_else(|| HoldStatus::Unreconciled.as_str().to_owned());
+    match db::holds::get_holds_by_status(&pool, &status).await {
+        Ok(holds) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} {} holds", holds.len(), status),
+                "holds": holds
+            })),
+        ),
+        Err(e) => {
+            log::error!(
+                "Error getting {} holds for {}: {}",
+                status,
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get holds",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/holds
+pub async fn resolve_hold(
+    Require { user, .. }: Require<ManageDrops>,
+    Extension(drop_service): Extension<DropService>,
+    Json(body): Json<HoldResolution>,
+) -> impl IntoResponse {
+    // Admins settle a hold by either charging the user for it now, or forgiving it
+    let status = match body.status.as_str() {
+        "committed" => HoldStatus::Committed,
+        "released" => HoldStatus::Released,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({
+                    "message": "A hold can only be resolved as 'committed' or 'released'"
+                })),
+            )
+        }
+    };
+
+    match drop_service
+        .settle_hold(body.id, status, &user.preferred_username)
+        .await
+    {
+        Ok(hold) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Hold {} for {} marked {}", hold.id, hold.username, hold.status),
+                "hold": hold
+            })),
+        ),
+        Err(e @ HoldError::NotFound(_)) | Err(e @ HoldError::NotUnreconciled { .. }) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": e.to_string() })),
+        ),
+        Err(e) => {
+            log::error!(
+                "Error resolving hold {} for {}: {}",
+                body.id,
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not resolve hold",
+                    "errorCode": 500,
+                    "message": e.to_string()
+                })),
+            )
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use super::machines::audit;
+use crate::db;
+use crate::db::api_keys::ApiScope;
+use crate::oidc::api_key;
+use crate::oidc::permissions::{ManageKeys, Require};
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewApiKey {
+    /// Who the key is for, like the name of the integration
+    name: String,
+    scopes: Vec<String>,
+    /// Never expires when missing
+    expires_at: Option<DateTime<Utc>>,
+}
+
+// GET /api/v2/keys
+pub async fn get_keys(
+    _: Require<ManageKeys>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+) -> impl IntoResponse {
+    match db::api_keys::get_api_keys(&pool).await {
+        Ok(keys) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} API keys", keys.len()),
+                "keys": keys
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error getting API keys: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get API keys",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// POST /api/v2/keys
+pub async fn create_key(
+    Require { user, .. }: Require<ManageKeys>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Json(body): Json<NewApiKey>,
+) -> impl IntoResponse {
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > 255 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The name must be between 1 and 255 characters" })),
+        );
+    }
+    let mut scopes = Vec::new();
+    for scope in &body.scopes {
+        match ApiScope::parse(scope) {
+            Some(scope) if !scopes.contains(&scope) => scopes.push(scope),
+            Some(_) => {}
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "message": format!("'{}' is not a valid scope", scope),
+                        "scopes": ApiScope::ALL.iter().map(ApiScope::as_str).collect::<Vec<_>>()
+                    })),
+                )
+            }
+        }
+    }
+    if scopes.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "A key needs at least one scope" })),
+        );
+    }
+    if body.expires_at.is_some_and(|expires| expires <= Utc::now()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The key has to expire in the future" })),
+        );
+    }
+
+    let new_key = api_key::generate();
+    match db::api_keys::create_api_key(
+        &pool,
+        name,
+        &new_key.prefix,
+        &new_key.hash,
+        &scopes,
+        &user.preferred_username,
+        body.expires_at,
+    )
+    .await
+    {
+        Ok(key) => {
+            log::info!(
+                "{} created API key {} ({}) with scopes {:?}",
+                user.preferred_username,
+                key.prefix,
+                key.name,
+                key.scopes
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "key.create",
+                &key.prefix,
+                json!({ "after": key }),
+            )
+            .await;
+            let mut resp = json!(key);
+            // This is the only time the key is ever shown
+            resp["key"] = json!(new_key.key);
+            (StatusCode::CREATED, Json(resp))
+        }
+        Err(e) => {
+            log::error!("Error creating API key {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not create API key",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// DELETE /api/v2/keys/:id
+pub async fn revoke_key(
+    Require { user, .. }: Require<ManageKeys>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(id): Path<i32>,
+) -> impl IntoResponse {
+    match db::api_keys::revoke_api_key(&pool, id, &user.preferred_username).await {
+        Ok(key) => {
+            log::info!(
+                "{} revoked API key {} ({})",
+                user.preferred_username,
+                key.prefix,
+                key.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "key.revoke",
+                &key.prefix,
+                json!({ "after": key }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Revoked API key {}", key.name),
+                    "key": key
+                })),
+            )
+        }
+        // Either it doesn't exist or it's already revoked
+        Err(sqlx::Error::RowNotFound) => match db::api_keys::get_api_key(&pool, id).await {
+            Ok(key) => (
+                StatusCode::CONFLICT,
+                Json(json!({
+                    "message": format!("API key {} is already revoked", key.name)
+                })),
+            ),
+            Err(_) => (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "message": format!("There is no API key with id {}", id)
+                })),
+            ),
+        },
+        Err(e) => {
+            log::error!("Error revoking API key {}: {}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not revoke API key",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
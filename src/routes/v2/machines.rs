@@ -0,0 +1,842 @@
+use crate::db;
+use crate::db::models;
+use crate::machine::signing;
+use crate::machine::status::StatusCache;
+use crate::oidc::auth::OIDCAuth;
+use crate::oidc::permissions::{ManageMachines, Require};
+use axum::extract::{Extension, Path, Query};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::env;
+use std::sync::Arc;
+
+/// How long a rotated-out secret keeps working, in seconds
+const DEFAULT_ROTATION_GRACE_SECS: i64 = 86400;
+
+/// Anything bigger is surely a typo
+const MAX_SLOT_OFFSET: i32 = 100;
+
+/// Names that would be shadowed by other routes under /api/v2/machines
+const RESERVED_NAMES: &[&str] = &["status"];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewMachine {
+    name: String,
+    display_name: String,
+    /// Defaults to true
+    active: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineChanges {
+    /// Renames the machine, which also changes its default URL
+    name: Option<String>,
+    display_name: Option<String>,
+    active: Option<bool>,
+    /// Added to slot numbers to get the ones bubbler's /drop uses
+    slot_offset: Option<i32>,
+}
+
+/// Fields that are missing are left alone, and null clears them
+#[derive(Deserialize)]
+pub struct MachineConnection {
+    /// Base URL of the machine's bubbler, cleared to use the URL template
+    #[serde(default, deserialize_with = "double_option")]
+    url: Option<Option<String>>,
+    /// Cleared to fall back to MACHINE_SECRET
+    #[serde(default, deserialize_with = "double_option")]
+    secret: Option<Option<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct SecretRotation {
+    /// Seconds the old secret stays valid, so the machine can be updated
+    grace: Option<i64>,
+}
+
+pub(crate) type Rejection = (StatusCode, Json<serde_json::Value>);
+
+/// Look up a machine by name, active or not
+pub(crate) async fn find_machine(
+    pool: &Pool<Postgres>,
+    name: &str,
+) -> Result<models::Machine, Rejection> {
+    match db::machines::get_any_machine(pool, name).await {
+        Ok(machine) => Ok(machine),
+        Err(sqlx::Error::RowNotFound) => Err((
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "message": format!("The machine name '{}' is not a valid machine", name)
+            })),
+        )),
+        Err(e) => {
+            log::error!("Error getting machine {}: {}", name, e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get machine",
+                    "errorCode": 500
+                })),
+            ))
+        }
+    }
+}
+
+/// Check a machine name, which has to work as a hostname for the URL template
+fn validate_name(name: &str) -> Result<(), Rejection> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !RESERVED_NAMES.contains(&name);
+    if valid {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "message": format!(
+                    "'{}' is not a valid machine name, use up to 63 lowercase letters, numbers and dashes",
+                    name
+                )
+            })),
+        ))
+    }
+}
+
+fn validate_display_name(display_name: &str) -> Result<(), Rejection> {
+    if display_name.trim().is_empty() || display_name.len() > 255 {
+        Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The display name must be between 1 and 255 characters" })),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Tells a missing field (`None`) apart from an explicit null (`Some(None)`)
+///
+/// Use with `#[serde(default, deserialize_with = "double_option")]`.
+pub(crate) fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+pub(crate) fn is_unique_violation(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("23505"),
+        _ => false,
+    }
+}
+
+pub(crate) fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(e) => e.code().as_deref() == Some("23503"),
+        _ => false,
+    }
+}
+
+/// Write an audit entry, logging rather than failing if it can't be saved
+pub(crate) async fn audit(
+    pool: &Pool<Postgres>,
+    actor: &str,
+    action: &str,
+    target: &str,
+    details: serde_json::Value,
+) {
+    if let Err(e) = db::audit::log_audit(pool, actor, action, target, &details).await {
+        log::error!(
+            "Error logging {} of {} by {}: {} ({})",
+            action,
+            target,
+            actor,
+            e,
+            details
+        );
+    }
+}
+
+fn connection_json(machine: &models::Machine) -> serde_json::Value {
+    json!({
+        "name": machine.name,
+        "url": machine.url,
+        "hasSecret": machine.secret.is_some(),
+        "previousSecretExpires": machine
+            .previous_secret_expires
+            .filter(|expires| *expires > Utc::now())
+    })
+}
+
+// GET /api/v2/machines
+pub async fn get_machines(
+    _: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+) -> impl IntoResponse {
+    match db::machines::get_all_machines(&pool).await {
+        Ok(machines) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} machines", machines.len()),
+                "machines": machines
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error getting machines: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get machines",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// POST /api/v2/machines
+pub async fn create_machine(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Json(body): Json<NewMachine>,
+) -> impl IntoResponse {
+    if let Err(rejection) =
+        validate_name(&body.name).and_then(|_| validate_display_name(&body.display_name))
+    {
+        return rejection;
+    }
+
+    let active = body.active.unwrap_or(true);
+    match db::machines::create_machine(&pool, &body.name, body.display_name.trim(), active).await {
+        Ok(machine) => {
+            log::info!(
+                "{} created machine {}",
+                user.preferred_username,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.create",
+                &machine.name,
+                json!({ "after": machine }),
+            )
+            .await;
+            (
+                StatusCode::CREATED,
+                Json(json!({
+                    "message": format!("Created machine {}", machine.name),
+                    "machine": machine
+                })),
+            )
+        }
+        Err(e) if is_unique_violation(&e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "message": format!("A machine named '{}' already exists", body.name)
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error creating machine {}: {}", body.name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not create machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/machines/:name
+pub async fn update_machine(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Json(body): Json<MachineChanges>,
+) -> impl IntoResponse {
+    if let Some(new_name) = &body.name {
+        if let Err(rejection) = validate_name(new_name) {
+            return rejection;
+        }
+    }
+    if let Some(display_name) = &body.display_name {
+        if let Err(rejection) = validate_display_name(display_name) {
+            return rejection;
+        }
+    }
+
+    if body
+        .slot_offset
+        .is_some_and(|offset| offset.abs() > MAX_SLOT_OFFSET)
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "message": format!("The slot offset must be between -{0} and {0}", MAX_SLOT_OFFSET)
+            })),
+        );
+    }
+
+    let before = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let new_name = body.name.as_deref().unwrap_or(&before.name);
+    let display_name = body
+        .display_name
+        .as_deref()
+        .map_or(before.display_name.as_str(), str::trim);
+    let active = body.active.unwrap_or(before.active);
+    let slot_offset = body.slot_offset.unwrap_or(before.slot_offset);
+    match db::machines::update_machine(
+        &pool,
+        before.id,
+        new_name,
+        display_name,
+        active,
+        slot_offset,
+    )
+    .await
+    {
+        Ok(machine) => {
+            log::info!(
+                "{} updated machine {}",
+                user.preferred_username,
+                before.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.update",
+                &before.name,
+                json!({ "before": before, "after": machine }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Updated machine {}", machine.name),
+                    "machine": machine
+                })),
+            )
+        }
+        Err(e) if is_unique_violation(&e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "message": format!("A machine named '{}' already exists", new_name)
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error updating machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// DELETE /api/v2/machines/:name
+pub async fn delete_machine(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    match db::machines::delete_machine(&pool, machine.id).await {
+        Ok(()) => {
+            log::info!(
+                "{} deleted machine {}",
+                user.preferred_username,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.delete",
+                &machine.name,
+                json!({ "before": machine }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Deleted machine {}", machine.name)
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error deleting machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not delete machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// GET /api/v2/machines/:name/connection
+pub async fn get_connection(
+    _: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match find_machine(&pool, &name).await {
+        Ok(machine) => (StatusCode::OK, Json(connection_json(&machine))),
+        Err(rejection) => rejection,
+    }
+}
+
+// PUT /api/v2/machines/:name/connection
+pub async fn put_connection(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Json(body): Json<MachineConnection>,
+) -> impl IntoResponse {
+    if let Some(Some(url)) = &body.url {
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => {}
+            _ => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({
+                        "message": format!("'{}' is not a valid http(s) URL", url)
+                    })),
+                )
+            }
+        }
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    if matches!(&body.secret, Some(Some(secret)) if secret.is_empty()) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The secret can't be empty, use null to clear it" })),
+        );
+    }
+
+    let before_url = machine.url.clone();
+    let url = match &body.url {
+        Some(url) => url.as_deref(),
+        None => machine.url.as_deref(),
+    };
+    let secret = match &body.secret {
+        Some(secret) => secret.as_deref(),
+        None => machine.secret.as_deref(),
+    };
+    match db::machines::set_connection(&pool, machine.id, url, secret).await {
+        Ok(machine) => {
+            log::info!(
+                "{} updated the connection for machine {}",
+                user.preferred_username,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.connection",
+                &machine.name,
+                json!({
+                    "before": { "url": before_url },
+                    "after": { "url": machine.url },
+                    "secretChanged": body.secret.is_some()
+                }),
+            )
+            .await;
+            (StatusCode::OK, Json(connection_json(&machine)))
+        }
+        Err(e) => {
+            log::error!("Error updating machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// POST /api/v2/machines/:name/secret
+pub async fn rotate_secret(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Json(body): Json<SecretRotation>,
+) -> impl IntoResponse {
+    let grace = body.grace.unwrap_or_else(|| {
+        env::var("SECRET_ROTATION_GRACE")
+            .ok()
+            .and_then(|grace| grace.parse().ok())
+            .unwrap_or(DEFAULT_ROTATION_GRACE_SECS)
+    });
+    if grace < 0 {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The grace period can't be negative" })),
+        );
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let secret = signing::generate_secret();
+    let expires = Utc::now() + Duration::seconds(grace);
+    match db::machines::rotate_secret(&pool, machine.id, &secret, expires).await {
+        Ok(machine) => {
+            log::info!(
+                "{} rotated the secret for machine {}",
+                user.preferred_username,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.secret",
+                &machine.name,
+                json!({ "previousSecretExpires": expires }),
+            )
+            .await;
+            let mut resp = connection_json(&machine);
+            // This is the only time the secret is ever shown
+            resp["secret"] = json!(secret);
+            (StatusCode::OK, Json(resp))
+        }
+        Err(e) => {
+            log::error!("Error rotating secret for machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not rotate secret",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// GET /api/v2/machines/status
+pub async fn get_statuses(
+    OIDCAuth(_user): OIDCAuth,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(statuses): Extension<StatusCache>,
+) -> impl IntoResponse {
+    let machines = match db::machines::get_active_machines(&pool).await {
+        Ok(machines) => machines,
+        Err(e) => {
+            log::error!("Error getting machines: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get machines",
+                    "errorCode": 500
+                })),
+            );
+        }
+    };
+
+    let machines: Vec<_> = machines
+        .iter()
+        .map(|machine| {
+            let status = statuses.cached(&machine.name);
+            json!({
+                "name": machine.name,
+                "displayName": machine.display_name,
+                "online": status.as_ref().is_some_and(|status| status.is_online()),
+                "maintenance": machine.maintenance(),
+                "status": status
+            })
+        })
+        .collect();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "message": format!("Retrieved status for {} machines", machines.len()),
+            "machines": machines
+        })),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceWindow {
+    /// Shown to anyone trying to use the machine
+    message: Option<String>,
+    /// Defaults to now, or schedule it for later
+    start: Option<DateTime<Utc>>,
+    /// Left open when missing, until maintenance is cleared
+    end: Option<DateTime<Utc>>,
+}
+
+// GET /api/v2/machines/:name/maintenance
+pub async fn get_maintenance(
+    OIDCAuth(_user): OIDCAuth,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    match find_machine(&pool, &name).await {
+        Ok(machine) => (
+            StatusCode::OK,
+            Json(json!({
+                "machine": machine.name,
+                "maintenance": machine.maintenance()
+            })),
+        ),
+        Err(rejection) => rejection,
+    }
+}
+
+// PUT /api/v2/machines/:name/maintenance
+pub async fn put_maintenance(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Json(body): Json<MaintenanceWindow>,
+) -> impl IntoResponse {
+    let now = Utc::now();
+    let start = body.start.unwrap_or(now);
+    if body.end.is_some_and(|end| end <= start.max(now)) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "Maintenance has to end after it starts, and in the future" })),
+        );
+    }
+    let message = body
+        .message
+        .as_deref()
+        .map(str::trim)
+        .filter(|message| !message.is_empty());
+    if message.is_some_and(|message| message.len() > 1000) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "The maintenance message can be at most 1000 characters" })),
+        );
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let before = machine.maintenance();
+    match db::machines::set_maintenance(&pool, machine.id, message, Some(start), body.end).await {
+        Ok(machine) => {
+            log::info!(
+                "{} scheduled maintenance for machine {} from {} to {:?}",
+                user.preferred_username,
+                machine.name,
+                start,
+                body.end
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.maintenance",
+                &machine.name,
+                json!({ "before": before, "after": machine.maintenance() }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "machine": machine.name,
+                    "maintenance": machine.maintenance()
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error updating machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// DELETE /api/v2/machines/:name/maintenance
+pub async fn delete_maintenance(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let before = machine.maintenance();
+    match db::machines::set_maintenance(&pool, machine.id, None, None, None).await {
+        Ok(machine) => {
+            log::info!(
+                "{} ended maintenance for machine {}",
+                user.preferred_username,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.maintenance",
+                &machine.name,
+                json!({ "before": before, "after": null }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "machine": machine.name,
+                    "maintenance": null
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error updating machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TemperatureQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TemperatureLimit {
+    /// None turns alerting off, unless TEMP_ALERT_THRESHOLD is set
+    max_temp: Option<f32>,
+}
+
+// GET /api/v2/machines/:name/temperature
+pub async fn get_temperature(
+    OIDCAuth(_user): OIDCAuth,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Query(params): Query<TemperatureQuery>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let since = params
+        .since
+        .unwrap_or_else(|| Utc::now() - Duration::hours(24));
+    match db::temperatures::get_samples(&pool, machine.id, since).await {
+        Ok(samples) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} temperature samples for {}", samples.len(), machine.name),
+                "machine": machine.name,
+                "maxTemp": machine.max_temp,
+                "samples": samples
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error getting temperatures for machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get temperatures",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/machines/:name/temperature
+pub async fn put_temperature(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+    Json(body): Json<TemperatureLimit>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let before_max_temp = machine.max_temp;
+    match db::machines::set_max_temp(&pool, machine.id, body.max_temp).await {
+        Ok(machine) => {
+            log::info!(
+                "{} set the temperature limit for machine {} to {:?}",
+                user.preferred_username,
+                machine.name,
+                machine.max_temp
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "machine.temperature",
+                &machine.name,
+                json!({
+                    "before": { "maxTemp": before_max_temp },
+                    "after": { "maxTemp": machine.max_temp }
+                }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "machine": machine.name,
+                    "maxTemp": machine.max_temp
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error updating machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update machine",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
@@ -1 +1,9 @@
+pub mod audit;
+pub mod drops;
+pub mod events;
+pub mod holds;
+pub mod keys;
+pub mod machines;
+pub mod slots;
 pub mod sms;
+pub mod users;
@@ -0,0 +1,598 @@
+use super::machines::{
+    audit, double_option, find_machine, is_foreign_key_violation, is_unique_violation, Rejection,
+};
+use crate::db;
+use crate::db::models;
+use crate::events::{Event, EventBus};
+use crate::machine::reconcile::{LayoutCheck, Reconciler};
+use crate::machine::status::StatusCache;
+use crate::oidc::auth::OIDCAuth;
+use crate::oidc::permissions::{ManageMachines, Require};
+use axum::extract::{Extension, Path, Query};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewSlot {
+    number: i32,
+    item_id: i32,
+    /// Defaults to false, like slots inserted by hand
+    active: Option<bool>,
+    /// Only set for machines that can't sense stock, like snack
+    count: Option<i32>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SlotChanges {
+    item_id: Option<i32>,
+    active: Option<bool>,
+    /// Missing leaves the count alone, null stops counting
+    #[serde(default, deserialize_with = "double_option")]
+    count: Option<Option<i32>>,
+}
+
+#[derive(Deserialize)]
+pub struct Layout {
+    slots: Vec<NewSlot>,
+}
+
+#[derive(Deserialize)]
+pub struct Force {
+    /// Skip checking the layout against what the machine reports
+    force: Option<bool>,
+}
+
+fn validate_slot(number: i32, count: Option<i32>) -> Result<(), Rejection> {
+    if number < 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": format!("{} is not a valid slot number", number) })),
+        ));
+    }
+    if count.is_some_and(|count| count < 0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": format!("The count for slot {} can't be negative", number) })),
+        ));
+    }
+    Ok(())
+}
+
+/// Make sure every slot in `numbers` is one the machine actually has
+///
+/// Slots the machine has but that aren't configured are fine here, since not
+/// every slot has to be set up.
+async fn check_slots(
+    statuses: &StatusCache,
+    machine: &models::Machine,
+    numbers: &[i32],
+) -> Result<LayoutCheck, Rejection> {
+    let state = match statuses.get_status(machine, true).await {
+        Ok(state) => state,
+        Err(e) => {
+            return Err((
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "message": format!(
+                        "Could not reach {} to check its slots, retry with force=true to skip the check: {}",
+                        machine.name, e
+                    )
+                })),
+            ))
+        }
+    };
+
+    let check = LayoutCheck::new(numbers, &state);
+    if check.missing.is_empty() {
+        Ok(check)
+    } else {
+        Err((
+            StatusCode::CONFLICT,
+            Json(json!({
+                "message": format!(
+                    "{} does not report some of these slots, retry with force=true to save them anyway",
+                    machine.name
+                ),
+                "layout": check
+            })),
+        ))
+    }
+}
+
+// GET /api/v2/machines/:name/slots
+pub async fn get_slots(
+    OIDCAuth(_user): OIDCAuth,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    match db::slots::get_machine_slots(&pool, machine.id).await {
+        Ok(slots) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} slots for {}", slots.len(), machine.name),
+                "machine": machine.name,
+                "slots": slots
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error getting slots for machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get slots",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// GET /api/v2/machines/:name/slots/check
+pub async fn check_layout(
+    _: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(statuses): Extension<StatusCache>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let slots = match db::slots::get_machine_slots(&pool, machine.id).await {
+        Ok(slots) => slots,
+        Err(e) => {
+            log::error!("Error getting slots for machine {}: {}", name, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get slots",
+                    "errorCode": 500
+                })),
+            );
+        }
+    };
+
+    match statuses.get_status(&machine, true).await {
+        Ok(state) => {
+            let numbers: Vec<i32> = slots.iter().map(|slot| slot.number).collect();
+            let check = LayoutCheck::new(&numbers, &state);
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": if check.matches() {
+                        format!("The slots for {} match the machine", machine.name)
+                    } else {
+                        format!("The slots for {} don't match the machine", machine.name)
+                    },
+                    "matches": check.matches(),
+                    "layout": check
+                })),
+            )
+        }
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "message": format!("Could not reach {} to check its slots: {}", machine.name, e)
+            })),
+        ),
+    }
+}
+
+// POST /api/v2/machines/:name/slots
+pub async fn create_slot(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(statuses): Extension<StatusCache>,
+    Path(name): Path<String>,
+    Query(params): Query<Force>,
+    Json(body): Json<NewSlot>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate_slot(body.number, body.count) {
+        return rejection;
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    if !params.force.unwrap_or(false) {
+        if let Err(rejection) = check_slots(&statuses, &machine, &[body.number]).await {
+            return rejection;
+        }
+    }
+
+    let slot = models::Slot {
+        machine: machine.id,
+        number: body.number,
+        item: Some(body.item_id),
+        active: body.active.unwrap_or(false),
+        count: body.count,
+    };
+    match db::slots::create_slot(&pool, &slot).await {
+        Ok(slot) => {
+            log::info!(
+                "{} added slot {} to machine {}",
+                user.preferred_username,
+                slot.number,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "slot.create",
+                &format!("{}/{}", machine.name, slot.number),
+                json!({ "after": slot }),
+            )
+            .await;
+            (
+                StatusCode::CREATED,
+                Json(json!({
+                    "message": format!("Added slot {} to {}", slot.number, machine.name),
+                    "slot": slot
+                })),
+            )
+        }
+        Err(e) if is_unique_violation(&e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "message": format!("{} already has a slot {}", machine.name, body.number)
+            })),
+        ),
+        Err(e) if is_foreign_key_violation(&e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "message": format!("The item id '{}' is not a valid item", body.item_id)
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error adding slot to machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not add slot",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/machines/:name/slots/:number
+pub async fn update_slot(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(events): Extension<EventBus>,
+    Path((name, number)): Path<(String, i32)>,
+    Json(body): Json<SlotChanges>,
+) -> impl IntoResponse {
+    if let Err(rejection) = validate_slot(number, body.count.flatten()) {
+        return rejection;
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let before = match db::slots::get_slot(&pool, machine.id, number).await {
+        Ok(slot) => slot,
+        Err(sqlx::Error::RowNotFound) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "message": format!("{} does not have a slot {}", machine.name, number)
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error getting slot {} of machine {}: {}", number, name, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get slot",
+                    "errorCode": 500
+                })),
+            );
+        }
+    };
+
+    let slot = models::Slot {
+        item: body.item_id.or(before.item),
+        active: body.active.unwrap_or(before.active),
+        count: body.count.unwrap_or(before.count),
+        ..before.clone()
+    };
+    match db::slots::update_slot(&pool, &slot).await {
+        Ok(slot) => {
+            log::info!(
+                "{} updated slot {} of machine {}",
+                user.preferred_username,
+                slot.number,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "slot.update",
+                &format!("{}/{}", machine.name, slot.number),
+                json!({ "before": before, "after": slot }),
+            )
+            .await;
+            if let Some(count) = slot.count.filter(|_| slot.count != before.count) {
+                events.publish(Event::SlotCount {
+                    machine: machine.name.clone(),
+                    slot: slot.number,
+                    count,
+                });
+            }
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Updated slot {} of {}", slot.number, machine.name),
+                    "slot": slot
+                })),
+            )
+        }
+        Err(e) if is_foreign_key_violation(&e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "message": format!("The item id '{}' is not a valid item", slot.item.unwrap_or_default())
+            })),
+        ),
+        Err(e) => {
+            log::error!("Error updating slot {} of machine {}: {}", number, name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not update slot",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// DELETE /api/v2/machines/:name/slots/:number
+pub async fn delete_slot(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Path((name, number)): Path<(String, i32)>,
+) -> impl IntoResponse {
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let before = match db::slots::get_slot(&pool, machine.id, number).await {
+        Ok(slot) => slot,
+        Err(sqlx::Error::RowNotFound) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(json!({
+                    "message": format!("{} does not have a slot {}", machine.name, number)
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error getting slot {} of machine {}: {}", number, name, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get slot",
+                    "errorCode": 500
+                })),
+            );
+        }
+    };
+
+    match db::slots::delete_slot(&pool, machine.id, number).await {
+        Ok(()) => {
+            log::info!(
+                "{} removed slot {} from machine {}",
+                user.preferred_username,
+                number,
+                machine.name
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "slot.delete",
+                &format!("{}/{}", machine.name, number),
+                json!({ "before": before }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Removed slot {} from {}", number, machine.name)
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!("Error removing slot {} of machine {}: {}", number, name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not remove slot",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// PUT /api/v2/machines/:name/slots
+pub async fn put_layout(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(statuses): Extension<StatusCache>,
+    Path(name): Path<String>,
+    Query(params): Query<Force>,
+    Json(body): Json<Layout>,
+) -> impl IntoResponse {
+    let mut numbers = HashSet::new();
+    for slot in &body.slots {
+        if let Err(rejection) = validate_slot(slot.number, slot.count) {
+            return rejection;
+        }
+        if !numbers.insert(slot.number) {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(
+                    json!({ "message": format!("Slot {} is listed more than once", slot.number) }),
+                ),
+            );
+        }
+    }
+
+    let machine = match find_machine(&pool, &name).await {
+        Ok(machine) => machine,
+        Err(rejection) => return rejection,
+    };
+
+    let numbers: Vec<i32> = body.slots.iter().map(|slot| slot.number).collect();
+    let check = if params.force.unwrap_or(false) {
+        None
+    } else {
+        match check_slots(&statuses, &machine, &numbers).await {
+            Ok(check) => Some(check),
+            Err(rejection) => return rejection,
+        }
+    };
+
+    let before = match db::slots::get_machine_slots(&pool, machine.id).await {
+        Ok(slots) => slots,
+        Err(e) => {
+            log::error!("Error getting slots for machine {}: {}", name, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get slots",
+                    "errorCode": 500
+                })),
+            );
+        }
+    };
+
+    let slots: Vec<models::Slot> = body
+        .slots
+        .iter()
+        .map(|slot| models::Slot {
+            machine: machine.id,
+            number: slot.number,
+            item: Some(slot.item_id),
+            active: slot.active.unwrap_or(false),
+            count: slot.count,
+        })
+        .collect();
+    match db::slots::set_layout(&pool, machine.id, &slots).await {
+        Ok(()) => {
+            log::info!(
+                "{} set the layout of machine {} to {} slots",
+                user.preferred_username,
+                machine.name,
+                slots.len()
+            );
+            audit(
+                &pool,
+                &user.preferred_username,
+                "slot.layout",
+                &machine.name,
+                json!({ "before": before, "after": slots }),
+            )
+            .await;
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!("Set {} slots for {}", slots.len(), machine.name),
+                    "slots": slots,
+                    "layout": check
+                })),
+            )
+        }
+        Err(e) if is_foreign_key_violation(&e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "message": "One of the item ids is not a valid item" })),
+        ),
+        Err(e) => {
+            log::error!("Error setting the layout of machine {}: {}", name, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not set slots",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
+
+// GET /api/v2/slots/reconcile
+pub async fn get_reconciliation(
+    _: Require<ManageMachines>,
+    Extension(reconciler): Extension<Reconciler>,
+) -> impl IntoResponse {
+    let results = reconciler.results();
+    (
+        StatusCode::OK,
+        Json(json!({
+            "message": format!("Retrieved reconciliation for {} machines", results.len()),
+            "machines": results
+        })),
+    )
+}
+
+// POST /api/v2/slots/reconcile
+pub async fn run_reconciliation(
+    Require { user, .. }: Require<ManageMachines>,
+    Extension(reconciler): Extension<Reconciler>,
+) -> impl IntoResponse {
+    match reconciler.run().await {
+        Ok(results) => {
+            let mismatched = results
+                .iter()
+                .filter(|result| !result.layout.as_ref().is_some_and(LayoutCheck::matches))
+                .count();
+            (
+                StatusCode::OK,
+                Json(json!({
+                    "message": format!(
+                        "Reconciled {} machines, {} need attention",
+                        results.len(),
+                        mismatched
+                    ),
+                    "machines": results
+                })),
+            )
+        }
+        Err(e) => {
+            log::error!(
+                "Error reconciling slots for {}: {}",
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not reconcile slots",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
@@ -1,14 +1,13 @@
 use crate::db;
-use crate::db::models::Drop;
+use crate::drop::incident::incident_message;
+use crate::drop::{is_slot_empty, DropError, DropRequest, DropService};
 use crate::ldap::client::LdapClient;
-use crate::ldap::user::LdapUserChangeSet;
-use crate::machine;
+use crate::machine::{self, status::StatusCache};
 use crate::oidc::auth::OIDCAuth;
 use axum::extract::Extension;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
-use chrono::Utc;
 use futures::stream::FuturesOrdered;
 use futures::StreamExt;
 use itertools::Itertools;
@@ -20,6 +19,8 @@ use std::sync::Arc;
 #[derive(Deserialize)]
 pub struct SmsMessage {
     message: String,
+    /// The gateway's id for this message, so a redelivered message isn't dropped twice
+    message_id: Option<String>,
 }
 
 // GET /api/v2/users/credits
@@ -28,6 +29,8 @@ pub async fn handle(
     OIDCAuth(user): OIDCAuth,
     Extension(mut ldap): Extension<LdapClient>,
     Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(drop_service): Extension<DropService>,
+    Extension(statuses): Extension<StatusCache>,
     Json(payload): Json<SmsMessage>,
 ) -> impl IntoResponse {
     log::info!(
@@ -38,11 +41,10 @@ pub async fn handle(
     let mut parts: Vec<String> = payload
         .message
         .split_whitespace()
-        .into_iter()
         .map(|s| s.to_owned())
         .collect();
 
-    let command = parts.get(0);
+    let command = parts.first();
     if command.is_none() {
         return (
             StatusCode::OK,
@@ -70,9 +72,9 @@ pub async fn handle(
             let machines = db::machines::get_active_machines(&pool).await.unwrap();
             let futures: FuturesOrdered<_> = machines
                 .iter()
-                .map(|m| machine::get_status(&m.name))
+                .map(|m| statuses.get_status(m, false))
                 .collect();
-            let machine_states: Vec<Result<machine::MachineResponse, reqwest::Error>> =
+            let machine_states: Vec<Result<machine::MachineResponse, machine::MachineError>> =
                 futures.collect().await;
 
             let resp = machines
@@ -85,14 +87,17 @@ pub async fn handle(
                                 Ok(r) => r.name == machine.name,
                                 _ => false,
                             });
-                    format!(
-                        "{}{}",
-                        machine.name,
-                        match online {
-                            true => "",
-                            false => " (offline)",
-                        }
-                    )
+                    match machine.maintenance() {
+                        Some(maintenance) => format!("{} ({})", machine.name, maintenance),
+                        None => format!(
+                            "{}{}",
+                            machine.name,
+                            match online {
+                                true => "",
+                                false => " (offline)",
+                            }
+                        ),
+                    }
                 })
                 .join("\n");
 
@@ -117,7 +122,16 @@ pub async fn handle(
             }
             let machine = machine.unwrap();
 
-            let machine_state = machine::get_status(&machine.name).await;
+            if let Some(maintenance) = machine.maintenance().filter(|m| m.active) {
+                return (
+                    StatusCode::OK,
+                    Json(json!({
+                        "message": format!("{} is {}", machine.display_name, maintenance)
+                    })),
+                );
+            }
+
+            let machine_state = statuses.get_status(&machine, false).await;
             if let Err(e) = machine_state {
                 log::error!(
                     "Error getting machine {} state for {}: {}",
@@ -151,23 +165,7 @@ pub async fn handle(
 
             let resp = slots
                 .iter()
-                .filter(|slot| {
-                    slot.active
-                        && match slot.count {
-                            Some(0) => false,
-                            Some(_) => true,
-                            _ => {
-                                match machine_state
-                                    .slots
-                                    .iter()
-                                    .find(|state_slot| state_slot.number == slot.number)
-                                {
-                                    Some(state_slot) => state_slot.stocked,
-                                    None => false,
-                                }
-                            }
-                        }
-                })
+                .filter(|slot| slot.active && !is_slot_empty(slot, Some(&machine_state)))
                 .map(|slot| format!("{} - {} ({}cr)", slot.number, slot.name, slot.price))
                 .join("\n");
 
@@ -177,9 +175,7 @@ pub async fn handle(
             // TODO: What if we could just drop with a drink name?? Unless...
             let item_name = parts.iter().skip(1).join(" ");
 
-            let matching_items = db::slots::search_item(&pool, &item_name).await;
-            if matching_items.is_ok() {
-                let matching_items = matching_items.unwrap();
+            if let Ok(matching_items) = db::slots::search_item(&pool, &item_name).await {
                 if matching_items.len() > 1 {
                     log::warn!(
                         "Rejecting request from {} to drop {}, too many matching items",
@@ -194,7 +190,7 @@ pub async fn handle(
                     );
                 }
                 if matching_items.len() == 1 {
-                    parts[1] = db::machines::get_active_machines(&pool)
+                    let machine_name = db::machines::get_active_machines(&pool)
                         .await
                         .unwrap()
                         .iter()
@@ -202,7 +198,9 @@ pub async fn handle(
                         .unwrap()
                         .name
                         .clone();
-                    parts[2] = matching_items[0].number.to_string();
+                    parts.truncate(1);
+                    parts.push(machine_name);
+                    parts.push(matching_items[0].number.to_string());
                 }
             }
 
@@ -219,21 +217,6 @@ pub async fn handle(
             }
             let machine_name = machine_name.unwrap().to_owned();
 
-            let machine = db::machines::get_machine(&pool, &machine_name).await;
-            if let Err(e) = machine {
-                log::error!(
-                    "Error getting machine {} for {}: {}",
-                    &machine_name,
-                    user.preferred_username,
-                    e
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({ "message": "Unknown machine" })),
-                );
-            }
-            let machine = machine.unwrap();
-
             let slot_num = parts.get(2);
             if slot_num.is_none() {
                 log::warn!(
@@ -262,210 +245,60 @@ pub async fn handle(
             }
             let slot_num = slot_num.unwrap();
 
-            let slot = db::slots::get_slot_with_item(&pool, machine.id, slot_num).await;
-            if let Err(e) = slot {
-                log::error!(
-                    "Error getting slot {} in machine {} for {}: {}",
-                    slot_num,
-                    machine.name,
-                    user.preferred_username,
-                    e
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({
-                        "message": format!("{} doesn't have that slot", machine.display_name)
-                    })),
-                );
-            }
-            let slot = slot.unwrap();
-
-            let machine_state = machine::get_status(&machine.name).await;
-            if let Err(e) = machine_state {
-                log::error!(
-                    "Error getting machine {} state for {}: {}",
-                    machine.name,
-                    user.preferred_username,
-                    e
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({
-                        "message": format!("{} is offline", machine.display_name)
-                    })),
-                );
-            }
-            let machine_state = machine_state.unwrap();
-            let slot_state = machine_state
-                .slots
-                .iter()
-                .find(|slot| slot.number == slot_num);
-
-            let slot_empty = match slot.count {
-                Some(0) => true,
-                Some(_) => false,
-                _ => match slot_state {
-                    Some(slot_state) => !slot_state.stocked,
-                    None => true,
-                },
+            let request = DropRequest {
+                username: user.preferred_username.clone(),
+                machine: machine_name,
+                slot: slot_num,
+                idempotency_key: payload.message_id.map(|id| format!("sms:{}", id)),
             };
-            if slot_empty {
-                log::warn!(
-                    "Rejecting request from {} to drop from machine {} slot {}, slot is empty",
-                    user.preferred_username,
-                    machine.name,
-                    slot.number,
-                );
-
-                return (
-                    StatusCode::OK,
-                    Json(json!({
-                        "message": format!("{} slot {} is empty", machine.display_name, slot.number)
-                    })),
-                );
-            }
-
-            let user = ldap.get_user(&user.preferred_username).await.unwrap();
-            if user.drinkBalance.unwrap_or(0) < slot.price.into() {
-                log::warn!(
-                    "Rejecting request from {} to drop a drink, insufficient drink credits",
-                    &user.uid,
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({ "message": "You don't have enough drink credits!" })),
-                );
-            }
-
-            let drop_response = machine::drop(&machine.name, slot.number).await;
-            if let Err(drop_error) = drop_response {
-                if drop_error.is_connect() {
-                    log::error!(
-                        "Error dropping drink for {}, could not connect to machine {}",
-                        user.uid,
-                        machine.name
-                    );
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "message":
-                                format!("Could not contact {} for drop!", machine.display_name)
-                        })),
-                    );
-                } else if drop_error.is_timeout() {
-                    log::error!(
-                        "Error dropping drink for {}, machine {} timed out",
-                        user.uid,
-                        machine.name
-                    );
-                    return (
-                        StatusCode::OK,
-                        Json(json!({
-                            "error": format!("Connection to {} timed out!", machine.display_name)
-                        })),
-                    );
+            let message = match drop_service.drop(&request).await {
+                Ok(outcome) => format!(
+                    "Dropped you a {} from {}! You have {} credits remaining",
+                    outcome.item, outcome.machine_display_name, outcome.new_balance
+                ),
+                Err(DropError::InvalidMachine(_)) => String::from("Unknown machine"),
+                Err(DropError::InvalidSlot { .. }) => {
+                    format!("{} doesn't have that slot", request.machine)
                 }
-
-                log::error!(
-                    "Error dropping drink for {}, an unknown error occured occured dropping a drink from machine {} slot {}",
-                    user.uid,
-                    machine.name,
-                    slot.number
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({ "message": "An unknown error occured while trying to drop :(" })),
-                );
-            }
-
-            let drop_response = drop_response.unwrap();
-
-            if drop_response.error_for_status_ref().is_err() {
-                let drop_content = drop_response.json::<serde_json::Value>().await.unwrap();
-                log::error!(
-                    "Error dropping drink for {}, an error occured occured dropping a drink from machine {} slot {}: {}",
-                    user.uid,
-                    machine.name,
-                    slot.number,
-                    drop_content["error"].as_str().unwrap()
-                );
-                return (
-                    StatusCode::OK,
-                    Json(json!({
-                        "message": "Could not access slot for drop",
-                    })),
-                );
-            }
-
-            let new_balance = user.drinkBalance.unwrap() - slot.price as i64;
-
-            let change_set = LdapUserChangeSet {
-                dn: user.clone().dn,
-                drinkBalance: Some(new_balance),
-                ibutton: None,
-            };
-            ldap.update_user(&change_set).await;
-
-            if machine.name == "snack" {
-                if db::slots::update_slot_count(
-                    &pool,
-                    machine.id,
-                    slot.number,
-                    slot.count.unwrap_or(1) - 1,
-                )
-                .await
-                .is_err()
-                {
-                    log::error!(
-                        "Error updating db after drop for {}, could not change machine {} slot {} count {}",
-                        user.uid,
-                        machine.name,
-                        slot.number,
-                        slot.count.unwrap_or(1) - 1
-                    );
+                Err(DropError::MachineOffline(machine)) => {
+                    format!("{} is offline", machine.display_name)
                 }
-                #[allow(clippy::collapsible_if)]
-                if slot.count.unwrap_or(1) == 1 {
-                    if db::slots::update_slot_active(&pool, machine.id, slot.number, false)
-                        .await
-                        .is_err()
-                    {
-                        log::error!(
-                            "Error updating db after drop for {}, could not change machine {} slot {} active {}",
-                            user.uid,
-                            machine.name,
-                            slot.number,
-                                false
-                        );
-                    }
+                Err(DropError::Maintenance(machine)) => match machine.maintenance() {
+                    Some(maintenance) => format!("{} is {}", machine.display_name, maintenance),
+                    None => format!("{} is down for maintenance", machine.display_name),
+                },
+                Err(DropError::SlotEmpty { machine, slot }) => {
+                    format!("{} slot {} is empty", machine.display_name, slot)
                 }
-            }
-
-            let drop = Drop {
-                id: 0,                 // placeholder,
-                timestamp: Utc::now(), // placeholder
-                username: user.uid.clone(),
-                machine: machine.id,
-                slot: slot.number,
-                item: slot.id,
-                item_name: slot.name.clone(),
-                item_price: slot.price,
+                Err(DropError::UserNotFound(_)) => String::from("Couldn't find your account"),
+                Err(DropError::InsufficientBalance { .. }) => {
+                    String::from("You don't have enough drink credits!")
+                }
+                Err(DropError::ConnectionFailed(machine)) => {
+                    format!("Could not contact {} for drop!", machine.display_name)
+                }
+                Err(DropError::TimedOut { machine, incident }) => format!(
+                    "Connection to {} timed out!{}",
+                    machine.display_name,
+                    incident_note(incident)
+                ),
+                Err(DropError::MachineError { incident, .. }) => {
+                    format!("Could not access slot for drop{}", incident_note(incident))
+                }
+                Err(DropError::MachineBusy { machine, .. }) => {
+                    format!("{} is busy, try again in a moment", machine.display_name)
+                }
+                Err(DropError::InProgress) => String::from("Your drop is still in progress"),
+                Err(DropError::KeyReused) => {
+                    String::from("That message was already used for a different drop")
+                }
+                Err(DropError::Unknown { incident, .. }) => format!(
+                    "An unknown error occured while trying to drop :({}",
+                    incident_note(incident)
+                ),
             };
-            if let Err(e) = db::drops::log_drop(&pool, &drop).await {
-                log::warn!("Error logging drop: {e}");
-            }
 
-            log::info!("Successfully dropped {} for {}", slot.name, &user.uid);
-            (
-                StatusCode::OK,
-                Json(json!({
-                    "message":
-                        format!(
-                            "Dropped you a {} from {}! You have {} credits remaining",
-                            slot.name, machine.display_name, new_balance
-                        )
-                })),
-            )
+            (StatusCode::OK, Json(json!({ "message": message })))
         }
         "commands" | "help" => (
             StatusCode::OK,
@@ -479,3 +312,10 @@ pub async fn handle(
         ),
     }
 }
+
+fn incident_note(incident: Option<i32>) -> String {
+    match incident {
+        Some(id) => format!("\n{}", incident_message(id)),
+        None => String::new(),
+    }
+}
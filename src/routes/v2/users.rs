@@ -0,0 +1,75 @@
+use crate::db;
+use crate::oidc::auth::OIDCAuth;
+use crate::oidc::permissions::{forbidden, Permission, Roles};
+use axum::extract::{Extension, Path, Query};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use std::sync::Arc;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct Page {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+// GET /api/v2/users/:uid/transactions
+pub async fn get_transactions(
+    OIDCAuth(user): OIDCAuth,
+    Extension(pool): Extension<Arc<Pool<Postgres>>>,
+    Extension(roles): Extension<Arc<Roles>>,
+    Path(uid): Path<String>,
+    Query(params): Query<Page>,
+) -> impl IntoResponse {
+    if !roles.allows(&user, Permission::ViewUsers) && user.preferred_username != uid {
+        return forbidden();
+    }
+
+    let page = params.page.unwrap_or(1).max(1);
+    let per_page = params
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = match (page - 1).checked_mul(per_page) {
+        Some(offset) => offset,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "message": format!("Page {} is out of range", page) })),
+            )
+        }
+    };
+
+    match db::transactions::get_user_transactions(&pool, &uid, per_page, offset).await {
+        Ok(transactions) => (
+            StatusCode::OK,
+            Json(json!({
+                "message": format!("Retrieved {} transactions for {}", transactions.len(), uid),
+                "page": page,
+                "per_page": per_page,
+                "transactions": transactions
+            })),
+        ),
+        Err(e) => {
+            log::error!(
+                "Error getting transactions for {} for {}: {}",
+                uid,
+                user.preferred_username,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": "Could not get transactions",
+                    "errorCode": 500
+                })),
+            )
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::db::models::Machine;
+use crate::machine::{MachineBackend, MachineError, MachineResponse, Machines};
+use async_trait::async_trait;
+use axum::extract::MatchedPath;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use metrics::{
+    describe_counter, describe_gauge, describe_histogram, gauge, histogram, increment_counter,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::time::Instant;
+
+/// Histogram buckets for everything measured in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0, 30.0,
+];
+
+/// Install the global Prometheus recorder, returning a handle to render it
+pub fn install() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix(String::from("_seconds")), LATENCY_BUCKETS)
+        .unwrap()
+        .install_recorder()
+        .unwrap();
+
+    describe_counter!(
+        "bartender_drops_total",
+        "Drop attempts by machine, slot and outcome"
+    );
+    describe_histogram!(
+        "bartender_machine_drop_duration_seconds",
+        "Time taken by the machine to answer a drop"
+    );
+    describe_histogram!(
+        "bartender_machine_status_duration_seconds",
+        "Time taken by the machine to answer a status request"
+    );
+    describe_gauge!(
+        "bartender_machine_online",
+        "Whether the machine answered its last status poll"
+    );
+    describe_gauge!(
+        "bartender_slot_mismatches",
+        "Slots that don't line up with the machine, as of the last reconciliation"
+    );
+    describe_gauge!(
+        "bartender_ldap_pool_size",
+        "LDAP connections currently open"
+    );
+    describe_gauge!(
+        "bartender_ldap_pool_available",
+        "Idle LDAP connections, negative when requests are waiting"
+    );
+    describe_gauge!("bartender_ldap_pool_max_size", "Maximum LDAP connections");
+    describe_counter!(
+        "bartender_token_cache_total",
+        "Token cache lookups by result (hit, negative_hit or miss)"
+    );
+    describe_counter!(
+        "bartender_http_requests_total",
+        "HTTP requests by method, route and status"
+    );
+    describe_histogram!(
+        "bartender_http_request_duration_seconds",
+        "Time taken to answer HTTP requests"
+    );
+
+    handle
+}
+
+/// Record a finished drop attempt
+pub fn record_drop(machine: &str, slot: String, outcome: &'static str) {
+    increment_counter!(
+        "bartender_drops_total",
+        "machine" => machine.to_owned(),
+        "slot" => slot,
+        "outcome" => outcome
+    );
+}
+
+/// Update the LDAP pool gauges, which are only sampled when scraped
+pub fn record_ldap_pool(status: &deadpool::Status) {
+    gauge!("bartender_ldap_pool_size", status.size as f64);
+    gauge!("bartender_ldap_pool_available", status.available as f64);
+    gauge!("bartender_ldap_pool_max_size", status.max_size as f64);
+}
+
+/// Count requests and time responses, labelled by the route they matched
+///
+/// This has to be a route layer, since the route is only known after routing.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    histogram!(
+        "bartender_http_request_duration_seconds",
+        start.elapsed().as_secs_f64(),
+        "method" => method.clone(),
+        "route" => route.clone()
+    );
+    increment_counter!(
+        "bartender_http_requests_total",
+        "method" => method,
+        "route" => route,
+        "status" => response.status().as_u16().to_string()
+    );
+    response
+}
+
+fn outcome<T>(result: &Result<T, MachineError>) -> &'static str {
+    match result {
+        Ok(_) => "ok",
+        Err(MachineError::Connect(_)) => "connect",
+        Err(MachineError::Timeout) => "timeout",
+        Err(MachineError::Status { .. }) => "status",
+        Err(MachineError::Other(_)) => "other",
+    }
+}
+
+/// Wraps a backend to time every call made to the machines
+pub struct MeteredBackend {
+    inner: Machines,
+}
+
+impl MeteredBackend {
+    #[must_use]
+    pub fn new(inner: Machines) -> Self {
+        MeteredBackend { inner }
+    }
+}
+
+#[async_trait]
+impl MachineBackend for MeteredBackend {
+    async fn get_status(&self, machine: &Machine) -> Result<MachineResponse, MachineError> {
+        let start = Instant::now();
+        let result = self.inner.get_status(machine).await;
+        histogram!(
+            "bartender_machine_status_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            "machine" => machine.name.clone(),
+            "outcome" => outcome(&result)
+        );
+        result
+    }
+
+    async fn drop_slot(&self, machine: &Machine, slot: i32) -> Result<(), MachineError> {
+        let start = Instant::now();
+        let result = self.inner.drop_slot(machine, slot).await;
+        histogram!(
+            "bartender_machine_drop_duration_seconds",
+            start.elapsed().as_secs_f64(),
+            "machine" => machine.name.clone(),
+            "outcome" => outcome(&result)
+        );
+        result
+    }
+}
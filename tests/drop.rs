@@ -0,0 +1,418 @@
+//! Drives `DropService` against the simulator, with accounts kept in memory
+//!
+//! These need a Postgres database to run against, named by TEST_DATABASE_URL, and
+//! are skipped when it isn't set. Migrations are run on it first.
+
+use async_trait::async_trait;
+use bartender::db;
+use bartender::db::holds::HoldStatus;
+use bartender::drop::hold::HoldError;
+use bartender::drop::{DropError, DropRequest, DropService};
+use bartender::events::EventBus;
+use bartender::ldap::user::LdapUser;
+use bartender::ldap::{AccountBackend, BalanceError};
+use bartender::machine::simulator::SimulatorBackend;
+use bartender::machine::MachineBackend;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+
+const PRICE: i32 = 5;
+const BALANCE: i64 = 20;
+
+/// Users and balances, standing in for LDAP
+#[derive(Default)]
+struct MemoryAccounts {
+    users: Mutex<HashMap<String, LdapUser>>,
+}
+
+impl MemoryAccounts {
+    fn add_user(&self, uid: &str, balance: i64) {
+        let user = LdapUser {
+            dn: format!("uid={uid},cn=users"),
+            cn: uid.to_owned(),
+            uid: uid.to_owned(),
+            groups: Vec::new(),
+            krbPrincipalName: uid.to_owned(),
+            mail: Vec::new(),
+            mobile: Vec::new(),
+            drinkBalance: Some(balance),
+            ibutton: Vec::new(),
+        };
+        self.users.lock().unwrap().insert(uid.to_owned(), user);
+    }
+
+    fn balance(&self, uid: &str) -> Option<i64> {
+        self.users.lock().unwrap()[uid].drinkBalance
+    }
+}
+
+#[async_trait]
+impl AccountBackend for MemoryAccounts {
+    async fn get_user(&self, uid: &str) -> Option<LdapUser> {
+        self.users.lock().unwrap().get(uid).cloned()
+    }
+
+    async fn update_balance(
+        &self,
+        dn: &str,
+        old: Option<i64>,
+        new: i64,
+    ) -> Result<(), BalanceError> {
+        let mut users = self.users.lock().unwrap();
+        let user = users
+            .values_mut()
+            .find(|user| user.dn == dn)
+            .ok_or(BalanceError::Conflict)?;
+        if user.drinkBalance != old {
+            return Err(BalanceError::Conflict);
+        }
+        user.drinkBalance = Some(new);
+        Ok(())
+    }
+}
+
+struct Fixture {
+    pool: Arc<Pool<Postgres>>,
+    service: DropService,
+    simulator: Arc<SimulatorBackend>,
+    accounts: Arc<MemoryAccounts>,
+    machine: String,
+    user: String,
+}
+
+impl Fixture {
+    /// A machine of its own with one active slot, and a user who can afford it
+    async fn new(simulator: SimulatorBackend) -> Option<Self> {
+        let url = match env::var("TEST_DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("TEST_DATABASE_URL is not set, skipping");
+                return None;
+            }
+        };
+        let pool = PgPoolOptions::new()
+            .max_connections(2)
+            .connect(&url)
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+
+        let suffix = rand::random::<u32>();
+        let machine = format!("test-{suffix}");
+        let user = format!("user-{suffix}");
+        let machine_id = db::machines::create_machine(&pool, &machine, "Test Machine", true)
+            .await
+            .unwrap()
+            .id;
+        let item: i32 =
+            sqlx::query_scalar("INSERT INTO items(name, price) VALUES ($1, $2) RETURNING id")
+                .bind("Test Soda")
+                .bind(PRICE)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        sqlx::query("INSERT INTO slots(machine, number, item, active) VALUES ($1, 1, $2, true)")
+            .bind(machine_id)
+            .bind(item)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let pool = Arc::new(pool);
+        let simulator = Arc::new(simulator);
+        let accounts = Arc::new(MemoryAccounts::default());
+        accounts.add_user(&user, BALANCE);
+        let service = DropService::new(
+            pool.clone(),
+            accounts.clone(),
+            simulator.clone(),
+            EventBus::new(),
+        );
+        Some(Fixture {
+            pool,
+            service,
+            simulator,
+            accounts,
+            machine,
+            user,
+        })
+    }
+
+    fn request(&self, idempotency_key: Option<&str>) -> DropRequest {
+        DropRequest {
+            username: self.user.clone(),
+            machine: self.machine.clone(),
+            slot: 1,
+            idempotency_key: idempotency_key.map(str::to_owned),
+        }
+    }
+
+    async fn incident_status(&self, incident: Option<i32>) -> String {
+        let incident = incident.expect("an incident should have been logged");
+        db::drops::get_drop(&self.pool, incident)
+            .await
+            .unwrap()
+            .status
+    }
+
+    async fn stocked(&self) -> bool {
+        let machine = db::machines::get_machine(&self.pool, &self.machine)
+            .await
+            .unwrap();
+        let status = self.simulator.get_status(&machine).await.unwrap();
+        status.slots[0].stocked
+    }
+}
+
+#[tokio::test]
+async fn drop_charges_the_user() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    fixture.simulator.set_stock(&fixture.machine, 1, 1);
+
+    let outcome = fixture.service.drop(&fixture.request(None)).await.unwrap();
+
+    assert_eq!(outcome.machine, fixture.machine);
+    assert_eq!(outcome.item, "Test Soda");
+    assert_eq!(outcome.new_balance, BALANCE - i64::from(PRICE));
+    assert_eq!(
+        fixture.accounts.balance(&fixture.user),
+        Some(BALANCE - i64::from(PRICE))
+    );
+    assert!(!fixture.stocked().await);
+}
+
+#[tokio::test]
+async fn machine_error_logs_a_failed_incident_without_charging() {
+    let fixture = match Fixture::new(SimulatorBackend::new().failure_rate(1.0)).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+
+    match fixture.service.drop(&fixture.request(None)).await {
+        Err(DropError::MachineError {
+            status, incident, ..
+        }) => {
+            assert_eq!(status, 500);
+            assert_eq!(fixture.incident_status(incident).await, "failed");
+        }
+        other => panic!("expected a machine error, got {other:?}"),
+    }
+    assert_eq!(fixture.accounts.balance(&fixture.user), Some(BALANCE));
+}
+
+#[tokio::test]
+async fn timeout_logs_an_unknown_incident_without_charging() {
+    let fixture = match Fixture::new(SimulatorBackend::new().timeout_rate(1.0)).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+
+    match fixture.service.drop(&fixture.request(None)).await {
+        Err(DropError::TimedOut { incident, .. }) => {
+            assert_eq!(fixture.incident_status(incident).await, "unknown");
+        }
+        other => panic!("expected a timeout, got {other:?}"),
+    }
+    // Held rather than charged, until a drink admin resolves the incident
+    assert_eq!(fixture.accounts.balance(&fixture.user), Some(BALANCE));
+}
+
+#[tokio::test]
+async fn empty_slot_is_rejected_before_dropping() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    fixture.simulator.set_stock(&fixture.machine, 1, 0);
+
+    match fixture.service.drop(&fixture.request(None)).await {
+        Err(DropError::SlotEmpty { slot, .. }) => assert_eq!(slot, 1),
+        other => panic!("expected an empty slot, got {other:?}"),
+    }
+    assert_eq!(fixture.accounts.balance(&fixture.user), Some(BALANCE));
+}
+
+#[tokio::test]
+async fn retried_drop_is_replayed_instead_of_dropped_again() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    fixture.simulator.set_stock(&fixture.machine, 1, 2);
+
+    let first = fixture
+        .service
+        .drop(&fixture.request(Some("retry")))
+        .await
+        .unwrap();
+    let retried = fixture
+        .service
+        .drop(&fixture.request(Some("retry")))
+        .await
+        .unwrap();
+
+    assert_eq!(retried.new_balance, first.new_balance);
+    assert_eq!(
+        fixture.accounts.balance(&fixture.user),
+        Some(BALANCE - i64::from(PRICE))
+    );
+    assert!(fixture.stocked().await);
+
+    let mut other_slot = fixture.request(Some("retry"));
+    other_slot.slot = 2;
+    assert!(matches!(
+        fixture.service.drop(&other_slot).await,
+        Err(DropError::KeyReused)
+    ));
+}
+
+#[tokio::test]
+async fn slot_without_an_item_is_listed_but_not_dropped() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    let machine = db::machines::get_machine(&fixture.pool, &fixture.machine)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO slots(machine, number, item, active) VALUES ($1, 2, NULL, true)")
+        .bind(machine.id)
+        .execute(fixture.pool.as_ref())
+        .await
+        .unwrap();
+    fixture.simulator.set_stock(&fixture.machine, 2, 1);
+
+    let slots = db::slots::get_machine_slots(&fixture.pool, machine.id)
+        .await
+        .unwrap();
+    let items: Vec<_> = slots.iter().map(|slot| (slot.number, slot.item)).collect();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[1], (2, None));
+
+    let mut request = fixture.request(None);
+    request.slot = 2;
+    assert!(matches!(
+        fixture.service.drop(&request).await,
+        Err(DropError::InvalidSlot { slot: 2, .. })
+    ));
+    assert_eq!(fixture.accounts.balance(&fixture.user), Some(BALANCE));
+}
+
+#[tokio::test]
+async fn settling_a_hold_charges_once_and_records_it() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    let hold: i32 = sqlx::query_scalar(
+        "INSERT INTO credit_holds(username, amount, status, error)
+        VALUES ($1, $2, 'unreconciled', 'LDAP was down') RETURNING id",
+    )
+    .bind(&fixture.user)
+    .bind(PRICE)
+    .fetch_one(fixture.pool.as_ref())
+    .await
+    .unwrap();
+
+    let settled = fixture
+        .service
+        .settle_hold(hold, HoldStatus::Committed, "admin")
+        .await
+        .unwrap();
+    assert_eq!(settled.status, "committed");
+    assert_eq!(
+        fixture.accounts.balance(&fixture.user),
+        Some(BALANCE - i64::from(PRICE))
+    );
+    let transactions = db::transactions::get_user_transactions(&fixture.pool, &fixture.user, 10, 0)
+        .await
+        .unwrap();
+    assert_eq!(transactions.len(), 1);
+    assert_eq!(transactions[0].kind, "drop");
+    assert_eq!(transactions[0].amount, -i64::from(PRICE));
+
+    assert!(matches!(
+        fixture
+            .service
+            .settle_hold(hold, HoldStatus::Committed, "admin")
+            .await,
+        Err(HoldError::NotUnreconciled { .. })
+    ));
+    assert_eq!(
+        fixture.accounts.balance(&fixture.user),
+        Some(BALANCE - i64::from(PRICE))
+    );
+}
+
+#[tokio::test]
+async fn abandoned_idempotency_claim_is_taken_over() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    fixture.simulator.set_stock(&fixture.machine, 1, 1);
+    let claim = |key: &'static str, age: &'static str| {
+        sqlx::query(
+            "INSERT INTO idempotency_keys(username, key, created_at)
+            VALUES ($1, $2, (now() AT TIME ZONE 'UTC') - $3::interval)",
+        )
+        .bind(fixture.user.clone())
+        .bind(key)
+        .bind(age)
+    };
+
+    // Still within the drop's time, so it may be running somewhere
+    claim("running", "1 second")
+        .execute(fixture.pool.as_ref())
+        .await
+        .unwrap();
+    assert!(matches!(
+        fixture
+            .service
+            .drop(&fixture.request(Some("running")))
+            .await,
+        Err(DropError::InProgress)
+    ));
+
+    // Left behind by a crash, long enough ago that the drop can't still be going
+    claim("crashed", "1 hour")
+        .execute(fixture.pool.as_ref())
+        .await
+        .unwrap();
+    let outcome = fixture
+        .service
+        .drop(&fixture.request(Some("crashed")))
+        .await
+        .unwrap();
+    assert_eq!(outcome.new_balance, BALANCE - i64::from(PRICE));
+}
+
+#[tokio::test]
+async fn slot_offset_only_shifts_the_dropped_slot() {
+    let fixture = match Fixture::new(SimulatorBackend::new()).await {
+        Some(fixture) => fixture,
+        None => return,
+    };
+    // Slot 1 is reported as stocked and slot 2 as empty, with the default offset
+    fixture.simulator.set_stock(&fixture.machine, 1, 1);
+    fixture.simulator.set_stock(&fixture.machine, 2, 0);
+    assert!(fixture.stocked().await);
+
+    // With an offset one too many the machine is told to drop from slot 2
+    sqlx::query("UPDATE machines SET slot_offset = 2 WHERE name = $1")
+        .bind(&fixture.machine)
+        .execute(fixture.pool.as_ref())
+        .await
+        .unwrap();
+    match fixture.service.drop(&fixture.request(None)).await {
+        Err(DropError::MachineError { status, .. }) => assert_eq!(status, 400),
+        other => panic!("expected the machine to refuse, got {other:?}"),
+    }
+    assert!(fixture.stocked().await);
+}